@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}, net::unix::UCred};
+
+/// how long a client has to provide its token before being dropped
+pub const AUTH_TIMEOUT : Duration = Duration::from_secs(30);
+const MAX_TOKEN_LEN : usize = 1024;
+
+/// ask client for the pre-shared token, reading one line. returns false for wrong
+/// tokens, clients taking too long and clients sending too much
+pub async fn challenge<S>(stream: &mut S, token: &str) -> std::io::Result<bool>
+where S: AsyncRead + AsyncWrite + Unpin {
+	stream.write_all(b"token: ").await?;
+	let ok = match receive_line(stream).await? {
+		Some(line) => token_matches(&line, token),
+		None => false,
+	};
+	if !ok {
+		stream.write_all(b"! authentication failed\n").await?;
+	}
+	Ok(ok)
+}
+
+/// read one line from a client which still has to authenticate, giving up
+/// (returning None) if it takes too long or sends too much
+pub async fn receive_line<S>(stream: &mut S) -> std::io::Result<Option<String>> where S: AsyncRead + Unpin {
+	match tokio::time::timeout(AUTH_TIMEOUT, read_line(stream)).await {
+		Ok(line) => Ok(line?.map(|l| l.trim_end_matches('\r').to_string())),
+		Err(_elapsed) => Ok(None),
+	}
+}
+
+pub fn token_matches(given: &str, token: &str) -> bool {
+	constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+async fn read_line<S>(stream: &mut S) -> std::io::Result<Option<String>> where S: AsyncRead + Unpin {
+	let mut buf = Vec::new();
+	loop {
+		match stream.read_u8().await? {
+			b'\n' => break,
+			c => buf.push(c),
+		}
+		if buf.len() > MAX_TOKEN_LEN {
+			return Ok(None);
+		}
+	}
+	Ok(String::from_utf8(buf).ok())
+}
+
+/// don't leak how much of the token matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// unix peers must match allowlists, when configured. uid 0 is not special
+pub fn peer_allowed(cred: &UCred, uids: &Option<Vec<u32>>, gids: &Option<Vec<u32>>) -> bool {
+	if let Some(uids) = uids {
+		if !uids.contains(&cred.uid()) { return false; }
+	}
+	if let Some(gids) = gids {
+		if !gids.contains(&cred.gid()) { return false; }
+	}
+	true
+}
@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+use mlua::{Lua, Error};
+use tracing::{info, error};
+
+/// run given script in given lua state. if {path} is a directory, every *.lua
+/// file inside is run, in alphabetical order.
+/// a script failing doesn't prevent the others from running. scripts run asynchronously,
+/// so they may sleep() or wait on timeout() at top level
+pub async fn autorun(lua: &Lua, path: &Path) -> Result<(), Error> {
+	for script in scripts(path)? {
+		let name = script.to_string_lossy().to_string();
+		match run(lua, &script, &name).await {
+			Ok(()) => info!("autorun script {} completed", name),
+			Err(e) => error!("autorun script {} failed: {}", name, e),
+		}
+	}
+
+	Ok(())
+}
+
+async fn run(lua: &Lua, script: &Path, name: &str) -> Result<(), Error> {
+	let code = std::fs::read(script)
+		.map_err(|e| Error::RuntimeError(format!("could not read {}: {}", name, e)))?;
+	lua.load(&code).set_name(format!("@{}", name))?.exec_async().await
+}
+
+fn scripts(path: &Path) -> Result<Vec<PathBuf>, Error> {
+	if !path.is_dir() {
+		return Ok(vec![path.to_path_buf()]);
+	}
+	let mut out = vec![];
+	let entries = std::fs::read_dir(path)
+		.map_err(|e| Error::RuntimeError(format!("could not list autorun dir {}: {}", path.display(), e)))?;
+	for entry in entries {
+		match entry {
+			Ok(e) => if e.path().extension().is_some_and(|x| x == "lua") && e.path().is_file() {
+				out.push(e.path());
+			},
+			Err(e) => error!("could not read autorun dir entry: {}", e),
+		}
+	}
+	out.sort();
+	Ok(out)
+}
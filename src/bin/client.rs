@@ -0,0 +1,398 @@
+#[allow(dead_code)]
+#[path = "../endpoint.rs"]
+mod endpoint;
+
+use std::{io::{Read, Write, ErrorKind}, path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use endpoint::Endpoint;
+use rustyline::{DefaultEditor, error::ReadlineError};
+use serde_json::{json, Value};
+
+const PROMPT : &str = "@> ";
+const CONTINUE : &str = "@    ";
+const TOKEN : &str = "token: ";
+/// sent to the repl to interrupt running evaluation
+const ETX : u8 = 0x03;
+
+#[derive(Parser)]
+#[command(author, version, about = "connect to processes infected by cordy")]
+struct CliArgs {
+	/// listen spec of target process: tcp:HOST:PORT, unix:/path or @name (tls is not supported)
+	#[arg(default_value = "127.0.0.1:13337")]
+	addr: String,
+
+	/// connect to default unix socket of cordy inside process {pid}
+	#[arg(short, long)]
+	pid: Option<u32>,
+
+	/// evaluate given code and exit, failing if it raises an error
+	#[arg(short, long)]
+	eval: Option<String>,
+
+	/// run given local lua file inside target process and exit
+	#[arg(short, long, conflicts_with = "eval")]
+	file: Option<PathBuf>,
+
+	/// token for authenticated channels
+	#[arg(short, long, env = "CORDY_TOKEN")]
+	token: Option<String>,
+
+	/// where to keep interactive history, defaults to ~/.cordy_history
+	#[arg(long)]
+	history: Option<PathBuf>,
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+fn main() -> ExitCode {
+	let args = CliArgs::parse();
+
+	let addr = match args.pid {
+		Some(pid) => Endpoint::unix_for(pid),
+		None => match args.addr.parse::<Endpoint>() {
+			Ok(addr) => addr,
+			Err(e) => {
+				eprintln!("! {}", e);
+				return ExitCode::from(2);
+			},
+		},
+	};
+
+	let mut remote = match Remote::connect(&addr, args.token.as_deref()) {
+		Ok(remote) => remote,
+		Err(e) => {
+			eprintln!("! could not connect to {}: {}", addr, e);
+			return ExitCode::from(2);
+		},
+	};
+
+	let code = match (&args.eval, &args.file) {
+		(Some(code), _) => Some((code.clone(), "=(eval)".to_string())),
+		(None, Some(path)) => match std::fs::read_to_string(path) {
+			Ok(code) => Some((code, format!("@{}", path.display()))),
+			Err(e) => {
+				eprintln!("! could not read {}: {}", path.display(), e);
+				return ExitCode::from(2);
+			},
+		},
+		(None, None) => None,
+	};
+
+	// the repl server can stop what it's evaluating, rpc ones can't
+	if remote.protocol == Protocol::Repl {
+		if let Some(out) = remote.interrupter.take() {
+			if let Err(e) = forward_interrupts(out) {
+				eprintln!("! could not handle ctrl-c, it will kill the client: {}", e);
+			}
+		}
+	}
+
+	let res = match code {
+		Some((code, name)) => remote.run(&code, &name),
+		None => remote.interactive(args.history.or_else(default_history)).map(|()| true),
+	};
+
+	match res {
+		Ok(true) => ExitCode::SUCCESS,
+		Ok(false) => ExitCode::FAILURE,
+		Err(e) => {
+			eprintln!("! connection error: {}", e);
+			ExitCode::from(2)
+		},
+	}
+}
+
+/// on ctrl-c, ask target to interrupt evaluation rather than dying
+fn forward_interrupts(mut out: Box<dyn Write + Send>) -> std::io::Result<()> {
+	let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGINT])?;
+	std::thread::spawn(move || {
+		for _ in signals.forever() {
+			if out.write_all(&[ETX]).and_then(|()| out.flush()).is_err() {
+				std::process::exit(2);
+			}
+		}
+	});
+	Ok(())
+}
+
+fn default_history() -> Option<PathBuf> {
+	std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cordy_history"))
+}
+
+/// what the target speaks: the character repl or the json rpc protocol
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+	Repl,
+	Rpc,
+}
+
+struct Remote {
+	stream: Box<dyn Stream>,
+	buf: Vec<u8>,
+	protocol: Protocol,
+	counter: u64,
+	/// another handle on the same connection, usable from other threads
+	interrupter: Option<Box<dyn Write + Send>>,
+}
+
+impl Remote {
+	fn connect(addr: &Endpoint, token: Option<&str>) -> std::io::Result<Self> {
+		let (stream, interrupter) : (Box<dyn Stream>, Box<dyn Write + Send>) = match addr {
+			Endpoint::Tcp(addr) => {
+				let stream = std::net::TcpStream::connect(addr)?;
+				(Box::new(stream.try_clone()?), Box::new(stream))
+			},
+			Endpoint::Unix(path) => {
+				let stream = std::os::unix::net::UnixStream::connect(path)?;
+				(Box::new(stream.try_clone()?), Box::new(stream))
+			},
+			Endpoint::Abstract(name) => {
+				use std::os::linux::net::SocketAddrExt;
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+				let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+				(Box::new(stream.try_clone()?), Box::new(stream))
+			},
+		};
+		let mut remote = Remote { stream, buf: Vec::new(), protocol: Protocol::Repl, counter: 0, interrupter: Some(interrupter) };
+
+		// rpc channels greet with a json line, repl channels with text
+		remote.fill()?;
+		if remote.buf.first() == Some(&b'{') {
+			remote.protocol = Protocol::Rpc;
+			remote.handshake_rpc(token)?;
+		} else {
+			remote.handshake_repl(token)?;
+		}
+		Ok(remote)
+	}
+
+	fn handshake_repl(&mut self, token: Option<&str>) -> std::io::Result<()> {
+		let (mut banner, mut prompt) = self.read_until_prompt()?;
+		if prompt == TOKEN {
+			let token = match token {
+				Some(t) => t.to_string(),
+				None => ask_token()?,
+			};
+			self.send(&format!("{}\n", token))?;
+			(banner, prompt) = self.read_until_prompt()?;
+		}
+		if prompt != PROMPT {
+			return Err(std::io::Error::new(ErrorKind::InvalidData, format!("unexpected greeting: {}{}", banner, prompt)));
+		}
+		eprint!("{}", banner);
+		Ok(())
+	}
+
+	fn handshake_rpc(&mut self, token: Option<&str>) -> std::io::Result<()> {
+		let hello : Value = serde_json::from_str(&self.read_line()?)?;
+		eprintln!("{} inside process #{}", hello["cordy"].as_str().unwrap_or("cordy"), hello["pid"]);
+		if hello["auth"].as_bool().unwrap_or(false) {
+			let token = match token {
+				Some(t) => t.to_string(),
+				None => ask_token()?,
+			};
+			self.send(&format!("{}\n", json!({ "token": token })))?;
+			let res : Value = serde_json::from_str(&self.read_line()?)?;
+			if let Some(err) = res["error"].as_str() {
+				return Err(std::io::Error::new(ErrorKind::PermissionDenied, err.to_string()));
+			}
+		}
+		Ok(())
+	}
+
+	/// run a whole chunk and print its results, returns false if it failed
+	fn run(&mut self, code: &str, name: &str) -> std::io::Result<bool> {
+		match self.protocol {
+			Protocol::Rpc => Ok(self.eval_rpc(code)?.is_none()),
+			Protocol::Repl => {
+				// the repl evaluates line by line and has no structured errors: send a single line
+				// which reports how the chunk went, tagged so that its own output can't fake it
+				let tag = format!("{:x}{:x} ", std::process::id(), std::time::SystemTime::now()
+					.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default());
+				let line = format!(
+					"(function(ok, ...) if ok then log({tag} .. 'ok') return ... end log({tag} .. 'error ' .. tostring((...))) end)\
+					(pcall(function() return (load({expr}, {name}) or assert(load({code}, {name})))() end))",
+					tag = lua_quote(&tag), expr = lua_quote(&format!("return {}", code)), code = lua_quote(code), name = lua_quote(name),
+				);
+				self.send(&format!("{}\n", line))?;
+				let (out, prompt) = self.read_until_prompt()?;
+				if prompt == CONTINUE {
+					eprintln!("! incomplete input");
+					return Ok(false);
+				}
+				let mut ok = false;
+				for line in out.split_inclusive('\n') {
+					match line.strip_prefix(tag.as_str()) {
+						Some(status) if status.trim_end() == "ok" => ok = true,
+						Some(status) => eprintln!("! {}", status.trim_end().trim_start_matches("error ")),
+						None => print!("{}", line),
+					}
+				}
+				Ok(ok) // no status at all means the evaluation got stopped
+			},
+		}
+	}
+
+	fn interactive(&mut self, history: Option<PathBuf>) -> std::io::Result<()> {
+		let mut rl = DefaultEditor::new().map_err(readline_error)?;
+		if let Some(path) = &history {
+			let _ = rl.load_history(path); // may not exist yet
+		}
+
+		let mut prompt = PROMPT;
+		let mut pending = String::new(); // only used in rpc mode, repl server keeps its own buffer
+		loop {
+			match rl.readline(prompt) {
+				Ok(line) => {
+					if !line.trim().is_empty() {
+						let _ = rl.add_history_entry(line.as_str());
+					}
+					if prompt == PROMPT && matches!(line.trim(), ".quit" | ".exit") {
+						break; // server would just close the connection
+					}
+					prompt = match self.protocol {
+						Protocol::Repl => {
+							self.send(&format!("{}\n", line))?;
+							let (out, prompt) = self.read_until_prompt()?;
+							print!("{}", out);
+							if prompt == CONTINUE { CONTINUE } else { PROMPT }
+						},
+						Protocol::Rpc => {
+							pending.push_str(&line);
+							pending.push('\n');
+							match self.eval_rpc(&pending)? {
+								Some(err) if err == "incomplete input" => CONTINUE,
+								_ => {
+									pending.clear();
+									PROMPT
+								},
+							}
+						},
+					};
+				},
+				Err(ReadlineError::Interrupted) => {
+					pending.clear();
+					if prompt == CONTINUE && self.protocol == Protocol::Repl {
+						// nothing is running, so the server just discards its buffer
+						self.stream.write_all(&[ETX])?;
+						self.stream.flush()?;
+						self.read_until_prompt()?;
+					}
+					prompt = PROMPT;
+				},
+				Err(ReadlineError::Eof) => break,
+				Err(e) => return Err(readline_error(e)),
+			}
+		}
+
+		if let Some(path) = &history {
+			if let Err(e) = rl.save_history(path) {
+				eprintln!("! could not save history to {}: {}", path.display(), e);
+			}
+		}
+		Ok(())
+	}
+
+	/// send one rpc request and print everything received until its response.
+	/// returns the error raised by the code, if any
+	fn eval_rpc(&mut self, code: &str) -> std::io::Result<Option<String>> {
+		self.counter += 1;
+		let id = self.counter;
+		self.send(&format!("{}\n", json!({ "id": id, "code": code })))?;
+		loop {
+			let msg : Value = serde_json::from_str(&self.read_line()?)?;
+			if let Some(text) = msg["text"].as_str() {
+				println!("{}", text);
+				continue;
+			}
+			if msg["id"] != json!(id) && !msg["id"].is_null() {
+				continue; // stale response
+			}
+			for log in msg["logs"].as_array().into_iter().flatten() {
+				println!("{}", log.as_str().unwrap_or_default());
+			}
+			for res in msg["results"].as_array().into_iter().flatten() {
+				println!("= {}", res);
+			}
+			return match msg["error"].as_str() {
+				Some(err) => {
+					if err != "incomplete input" {
+						println!("! {}", err);
+					}
+					Ok(Some(err.to_string()))
+				},
+				None => Ok(None),
+			};
+		}
+	}
+
+	fn send(&mut self, txt: &str) -> std::io::Result<()> {
+		self.stream.write_all(txt.as_bytes())?;
+		self.stream.flush()
+	}
+
+	fn fill(&mut self) -> std::io::Result<()> {
+		let mut chunk = [0u8; 4096];
+		match self.stream.read(&mut chunk)? {
+			0 => Err(std::io::Error::new(ErrorKind::UnexpectedEof, String::from_utf8_lossy(&self.buf).to_string())),
+			n => {
+				self.buf.extend_from_slice(&chunk[..n]);
+				Ok(())
+			},
+		}
+	}
+
+	fn read_line(&mut self) -> std::io::Result<String> {
+		loop {
+			if let Some(pos) = self.buf.iter().position(|c| *c == b'\n') {
+				let line : Vec<u8> = self.buf.drain(..=pos).collect();
+				return Ok(String::from_utf8_lossy(&line).to_string());
+			}
+			self.fill()?;
+		}
+	}
+
+	/// read repl output until it asks for more input, returning output and prompt
+	fn read_until_prompt(&mut self) -> std::io::Result<(String, &'static str)> {
+		loop {
+			for prompt in [PROMPT, CONTINUE, TOKEN] {
+				if self.buf.ends_with(prompt.as_bytes()) {
+					let out : Vec<u8> = self.buf.drain(..).collect();
+					let out = String::from_utf8_lossy(&out[..out.len() - prompt.len()]).to_string();
+					return Ok((out, prompt));
+				}
+			}
+			self.fill()?;
+		}
+	}
+}
+
+fn ask_token() -> std::io::Result<String> {
+	let mut rl = DefaultEditor::new().map_err(readline_error)?;
+	rl.readline(TOKEN).map_err(readline_error)
+}
+
+fn readline_error(e: ReadlineError) -> std::io::Error {
+	match e {
+		ReadlineError::Io(e) => e,
+		e => std::io::Error::other(e),
+	}
+}
+
+/// quote given text as a lua string literal
+fn lua_quote(txt: &str) -> String {
+	let mut out = String::from("\"");
+	for b in txt.bytes() {
+		match b {
+			b'\\' => out.push_str("\\\\"),
+			b'"' => out.push_str("\\\""),
+			b'\n' => out.push_str("\\n"),
+			0x20..=0x7e => out.push(b as char),
+			_ => out.push_str(&format!("\\{:03}", b)),
+		}
+	}
+	out.push('"');
+	out
+}
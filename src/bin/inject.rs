@@ -0,0 +1,256 @@
+#[allow(dead_code)]
+#[path = "../endpoint.rs"]
+mod endpoint;
+
+use std::{ffi::c_char, fs::OpenOptions, os::unix::fs::FileExt, path::{Path, PathBuf}, process::ExitCode, time::{Duration, Instant}};
+
+use clap::Parser;
+use endpoint::Endpoint;
+use nix::{sys::{ptrace, signal::Signal, wait::{waitpid, WaitStatus}}, unistd::Pid};
+use procfs::process::{MMapPath, Process};
+
+#[derive(Parser)]
+#[command(author, version, about = "load cordy inside a running process")]
+struct CliArgs {
+	/// pid of target process
+	pid: i32,
+
+	/// shared object to load, defaults to libcordy.so next to this binary
+	#[arg(short, long)]
+	lib: Option<PathBuf>,
+
+	/// once loaded, wait until given listen spec accepts connections
+	#[arg(short, long, num_args = 0..=1, default_missing_value = "127.0.0.1:13337")]
+	wait: Option<String>,
+
+	/// how many seconds to wait for control channel
+	#[arg(long, default_value_t = 10)]
+	timeout: u64,
+}
+
+fn main() -> ExitCode {
+	let args = CliArgs::parse();
+
+	let lib = match args.lib.clone().map_or_else(default_lib, Ok).and_then(|p| p.canonicalize().map_err(|e| format!("invalid lib {}: {}", p.display(), e))) {
+		Ok(lib) => lib,
+		Err(e) => {
+			eprintln!("! {}", e);
+			return ExitCode::FAILURE;
+		},
+	};
+
+	let wait = match args.wait.as_deref().map(str::parse::<Endpoint>).transpose() {
+		Ok(wait) => wait,
+		Err(e) => {
+			eprintln!("! {}", e);
+			return ExitCode::FAILURE;
+		},
+	};
+
+	match inject(Pid::from_raw(args.pid), &lib) {
+		Ok(handle) => eprintln!("loaded {} in process #{} (handle 0x{:X})", lib.display(), args.pid, handle),
+		Err(e) => {
+			eprintln!("! could not inject into process #{}: {}", args.pid, e);
+			return ExitCode::FAILURE;
+		},
+	}
+
+	if let Some(addr) = wait {
+		if let Err(e) = wait_for(&addr, Duration::from_secs(args.timeout)) {
+			eprintln!("! control channel {} is not up: {}", addr, e);
+			return ExitCode::FAILURE;
+		}
+		eprintln!("control channel {} is up", addr);
+	}
+
+	ExitCode::SUCCESS
+}
+
+fn default_lib() -> Result<PathBuf, String> {
+	let exe = std::env::current_exe().map_err(|e| format!("could not find own path: {}", e))?;
+	Ok(exe.with_file_name("libcordy.so"))
+}
+
+fn wait_for(addr: &Endpoint, timeout: Duration) -> std::io::Result<()> {
+	let start = Instant::now();
+	loop {
+		let res = match addr {
+			Endpoint::Tcp(addr) => std::net::TcpStream::connect(addr).map(|_| ()),
+			Endpoint::Unix(path) => std::os::unix::net::UnixStream::connect(path).map(|_| ()),
+			Endpoint::Abstract(name) => {
+				use std::os::linux::net::SocketAddrExt;
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+				std::os::unix::net::UnixStream::connect_addr(&addr).map(|_| ())
+			},
+		};
+		match res {
+			Ok(()) => return Ok(()),
+			Err(e) if start.elapsed() > timeout => return Err(e),
+			Err(_) => std::thread::sleep(Duration::from_millis(100)),
+		}
+	}
+}
+
+/// find where {symbol} lives inside process {pid}, assuming it's loaded from the same
+/// library as in our own process: take our own offset from library base, add it to
+/// the base of the same library inside target
+fn remote_symbol(pid: Pid, symbol: &[u8]) -> Result<u64, String> {
+	let local = unsafe { nix::libc::dlsym(nix::libc::RTLD_DEFAULT, symbol.as_ptr() as *const c_char) } as u64;
+	if local == 0 {
+		return Err(format!("could not resolve {} locally", String::from_utf8_lossy(symbol)));
+	}
+
+	let own_maps = Process::myself().and_then(|p| p.maps())
+		.map_err(|e| format!("could not read own maps: {}", e))?;
+	let module = own_maps.memory_maps.iter()
+		.find(|m| m.address.0 <= local && local < m.address.1)
+		.and_then(|m| match &m.pathname { MMapPath::Path(p) => Some(p.clone()), _ => None })
+		.ok_or("could not find library providing dlopen")?;
+	let local_base = base_of(&own_maps.memory_maps, &module, false)
+		.ok_or("could not find own library base")?;
+
+	let target_maps = Process::new(pid.as_raw()).and_then(|p| p.maps())
+		.map_err(|e| format!("could not read target maps: {}", e))?;
+	let remote_base = base_of(&target_maps.memory_maps, &module, false)
+		.or_else(|| base_of(&target_maps.memory_maps, &module, true)) // maybe another mount namespace
+		.ok_or_else(|| format!("target did not load {}", module.display()))?;
+
+	Ok(remote_base + (local - local_base))
+}
+
+/// lowest address mapped from given library. can match just file names
+fn base_of(maps: &[procfs::process::MemoryMap], module: &Path, by_name: bool) -> Option<u64> {
+	maps.iter()
+		.filter(|m| match &m.pathname {
+			MMapPath::Path(p) if by_name => p.file_name() == module.file_name(),
+			MMapPath::Path(p) => p == module,
+			_ => false,
+		})
+		.map(|m| m.address.0)
+		.min()
+}
+
+/// continue target until it stops because of a SIGSEGV, forwarding other signals
+fn run_until_fault(pid: Pid) -> Result<(), String> {
+	ptrace::cont(pid, None).map_err(|e| format!("could not resume target: {}", e))?;
+	loop {
+		match waitpid(pid, None) {
+			Ok(WaitStatus::Stopped(_, Signal::SIGSEGV)) => return Ok(()),
+			Ok(WaitStatus::Stopped(_, sig)) => ptrace::cont(pid, sig)
+				.map_err(|e| format!("could not resume target: {}", e))?,
+			Ok(WaitStatus::Exited(_, code)) => return Err(format!("target exited with code {}", code)),
+			Ok(WaitStatus::Signaled(_, sig, _)) => return Err(format!("target killed by {}", sig)),
+			Ok(_) => continue,
+			Err(e) => return Err(format!("could not wait for target: {}", e)),
+		}
+	}
+}
+
+/// make target thread call dlopen({lib}, RTLD_NOW) and return the handle it got.
+/// target is stopped, hijacked to call dlopen with a null return address (so we
+/// regain control once it faults returning) and then restored as it was
+#[cfg(target_arch = "x86_64")]
+fn inject(pid: Pid, lib: &Path) -> Result<u64, String> {
+	let dlopen = remote_symbol(pid, b"dlopen\0")?;
+
+	ptrace::attach(pid).map_err(|e| format!("could not attach: {}", e))?;
+	match waitpid(pid, None) {
+		Ok(WaitStatus::Stopped(_, _)) => {},
+		Ok(status) => return Err(format!("unexpected status after attach: {:?}", status)),
+		Err(e) => return Err(format!("could not wait for target: {}", e)),
+	}
+
+	let res = hijack(pid, dlopen, lib);
+
+	if let Err(e) = ptrace::detach(pid, None) {
+		eprintln!("! could not detach from target: {}", e);
+	}
+
+	match res? {
+		0 => Err("dlopen failed inside target".into()),
+		handle => Ok(handle),
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+fn hijack(pid: Pid, dlopen: u64, lib: &Path) -> Result<u64, String> {
+	use std::os::unix::ffi::OsStrExt;
+
+	let saved = ptrace::getregs(pid).map_err(|e| format!("could not read registers: {}", e))?;
+	let mem = OpenOptions::new().read(true).write(true).open(format!("/proc/{}/mem", pid))
+		.map_err(|e| format!("could not open target memory: {}", e))?;
+
+	let mut path = lib.as_os_str().as_bytes().to_vec();
+	path.push(0);
+
+	// stay well clear of the red zone, keep stack 16 bytes aligned at call site
+	let path_addr = (saved.rsp - 512 - path.len() as u64) & !0xF;
+	let ret_addr = path_addr - 16;
+	let scratch_len = (saved.rsp - ret_addr) as usize;
+	let mut backup = vec![0u8; scratch_len];
+	mem.read_exact_at(&mut backup, ret_addr)
+		.map_err(|e| format!("could not backup target stack: {}", e))?;
+
+	let res = (|| {
+		mem.write_all_at(&path, path_addr).map_err(|e| format!("could not write lib path: {}", e))?;
+		mem.write_all_at(&0u64.to_ne_bytes(), ret_addr + 8).map_err(|e| format!("could not write return address: {}", e))?;
+
+		let mut regs = saved;
+		regs.rip = dlopen;
+		regs.rdi = path_addr;
+		regs.rsi = nix::libc::RTLD_NOW as u64;
+		regs.rsp = ret_addr + 8;
+		regs.rax = 0;
+		regs.orig_rax = u64::MAX; // don't let the kernel restart an interrupted syscall
+		ptrace::setregs(pid, regs).map_err(|e| format!("could not set registers: {}", e))?;
+
+		run_until_fault(pid)?;
+
+		let after = ptrace::getregs(pid).map_err(|e| format!("could not read registers: {}", e))?;
+		if after.rip != 0 {
+			return Err(format!("target crashed inside dlopen at 0x{:X}", after.rip));
+		}
+		Ok(after.rax)
+	})();
+
+	if let Err(e) = mem.write_all_at(&backup, ret_addr) {
+		eprintln!("! could not restore target stack: {}", e);
+	}
+	ptrace::setregs(pid, saved).map_err(|e| format!("could not restore registers: {}", e))?;
+
+	res
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn inject(_pid: Pid, _lib: &Path) -> Result<u64, String> {
+	Err("injection is only implemented for x86_64".into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn injects_into_a_child_and_waits_for_its_channel() {
+		// test binaries live in target/<profile>/deps, the library one level up
+		let exe = std::env::current_exe().unwrap();
+		let lib = exe.parent().and_then(Path::parent).unwrap().join("libcordy.so");
+		assert!(lib.exists(), "build the library first: {}", lib.display());
+
+		let name = format!("@cordy-inject-test-{}", std::process::id());
+		let mut child = std::process::Command::new("sleep")
+			.arg("30")
+			.env("CORDY_LISTEN", &name)
+			.env_remove("CORDY_CONFIG")
+			.spawn()
+			.unwrap();
+		std::thread::sleep(Duration::from_millis(200)); // let the loader settle
+
+		let pid = Pid::from_raw(child.id() as i32);
+		let res = inject(pid, &lib.canonicalize().unwrap())
+			.and_then(|_| wait_for(&name.parse().unwrap(), Duration::from_secs(10)).map_err(|e| e.to_string()));
+		child.kill().ok();
+		child.wait().ok();
+		res.unwrap();
+	}
+}
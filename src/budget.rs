@@ -0,0 +1,285 @@
+use std::{cell::{Cell, RefCell}, future::Future, pin::Pin, rc::Rc, sync::{Arc, atomic::{AtomicBool, Ordering}}, task::{Context, Poll}, time::Duration};
+
+use mlua::Lua;
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{config::Config, helpers::sleep_until_deadline};
+
+/// running lua code is checked every this many vm instructions
+const HOOK_STEP : u32 = 1000;
+
+pub const INTERRUPTED : &str = "interrupted";
+const OUT_OF_TIME : &str = "time budget exceeded";
+const OUT_OF_INSTRUCTIONS : &str = "instruction budget exceeded";
+
+thread_local! {
+	/// budget of the evaluation being polled on this thread, if any
+	static RUNNING: RefCell<Option<Rc<Budget>>> = const { RefCell::new(None) };
+}
+
+/// lets a session stop the evaluation it's waiting for, from another task
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<Flags>);
+
+#[derive(Default)]
+struct Flags {
+	armed: AtomicBool,
+	triggered: AtomicBool,
+	notify: Notify,
+}
+
+impl Interrupt {
+	/// stop running evaluation. returns false if nothing is running
+	pub fn trigger(&self) -> bool {
+		if !self.0.armed.load(Ordering::Acquire) {
+			return false;
+		}
+		self.0.triggered.store(true, Ordering::Release);
+		self.0.notify.notify_waiters();
+		true
+	}
+
+	fn triggered(&self) -> bool {
+		self.0.triggered.load(Ordering::Acquire)
+	}
+
+	/// an evaluation is starting: forget previous triggers, and accept new ones until disarmed
+	fn arm(&self) -> Armed<'_> {
+		self.0.triggered.store(false, Ordering::Release);
+		self.0.armed.store(true, Ordering::Release);
+		Armed(self)
+	}
+
+	async fn wait(&self) {
+		loop {
+			let notified = self.0.notify.notified();
+			if self.triggered() {
+				return;
+			}
+			notified.await;
+		}
+	}
+}
+
+struct Armed<'a>(&'a Interrupt);
+
+impl Drop for Armed<'_> {
+	fn drop(&mut self) {
+		self.0.0.armed.store(false, Ordering::Release);
+	}
+}
+
+/// how long a single evaluation may run, and how to stop it early
+#[derive(Clone, Default)]
+pub struct Limits {
+	pub interrupt: Interrupt,
+	pub timeout: Option<Duration>,
+	pub instructions: Option<u64>,
+	/// stop lua code while it runs too, not just while it waits: needs the hook
+	pub hooked: bool,
+}
+
+impl Limits {
+	pub fn new(cfg: &Config) -> Self {
+		Limits {
+			interrupt: Interrupt::default(),
+			timeout: cfg.eval_timeout(),
+			instructions: cfg.eval_instructions,
+			hooked: cfg.interrupts || cfg.eval_timeout.is_some() || cfg.eval_instructions.is_some(),
+		}
+	}
+}
+
+/// what is left to one running evaluation
+struct Budget {
+	interrupt: Interrupt,
+	timeout: Option<Duration>,
+	/// restart timeout and instruction count every time the evaluation gets polled
+	sliced: bool,
+	deadline: Cell<Option<Instant>>,
+	instructions: Option<u64>,
+	spent: Cell<u64>,
+	/// why evaluation got stopped, first reason wins
+	stopped: Cell<Option<&'static str>>,
+}
+
+impl Budget {
+	fn check(&self) -> Option<&'static str> {
+		let reason = if self.interrupt.triggered() {
+			Some(INTERRUPTED)
+		} else if self.deadline.get().is_some_and(|d| Instant::now() >= d) {
+			Some(OUT_OF_TIME)
+		} else if self.instructions.is_some_and(|max| self.spent.get() > max) {
+			Some(OUT_OF_INSTRUCTIONS)
+		} else {
+			None
+		};
+		if reason.is_some() && self.stopped.get().is_none() {
+			self.stopped.set(reason);
+		}
+		reason
+	}
+}
+
+/// polls inner future with its budget set as the running one
+struct Tracked<F> {
+	inner: Pin<Box<F>>,
+	budget: Rc<Budget>,
+}
+
+impl<F: Future> Future for Tracked<F> {
+	type Output = F::Output;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		if self.budget.sliced {
+			self.budget.deadline.set(self.budget.timeout.map(|t| Instant::now() + t));
+			self.budget.spent.set(0);
+		}
+		let prev = RUNNING.with(|r| r.replace(Some(self.budget.clone())));
+		let res = self.inner.as_mut().poll(cx);
+		RUNNING.with(|r| *r.borrow_mut() = prev);
+		res
+	}
+}
+
+/// marks states where the hook is in place
+struct Hooked;
+
+/// install the hook stopping lua code which ran out of budget, once per state. luajit
+/// won't call hooks from compiled traces, so jit gets turned off or tight loops couldn't
+/// be stopped: states only get here when a session evaluates with hooked limits, so
+/// autorun scripts keep running compiled unless interrupts or budgets are configured.
+/// the hook is a lua function raising with error(): lua errors thrown from rust frames
+/// take the whole process down with luajit, so rust only tells it why to stop
+pub fn install(lua: &Lua) -> Result<(), mlua::Error> {
+	if lua.app_data_ref::<Hooked>().is_some() {
+		return Ok(());
+	}
+	let check = lua.create_function(|_lua, ()| {
+		Ok(RUNNING.with(|r| match r.borrow().as_ref() {
+			Some(budget) => {
+				budget.spent.set(budget.spent.get() + HOOK_STEP as u64);
+				budget.check()
+			},
+			None => None,
+		}))
+	})?;
+	lua.load(HOOK).set_name("=budget")?.call::<_, ()>((check, HOOK_STEP))?;
+	lua.set_app_data(Hooked);
+	Ok(())
+}
+
+const HOOK : &str = r#"
+	local check, step = ...
+	local error = error
+	if jit then jit.off() end
+	debug.sethook(function()
+		local reason = check()
+		if reason then error(reason, 0) end
+	end, "", step)
+"#;
+
+/// whether lua code running now is a session evaluation, rather than a background task
+/// or a script: requests meant for the session only make sense from there
+pub fn in_evaluation() -> bool {
+	RUNNING.with(|r| r.borrow().as_ref().is_some_and(|b| !b.sliced))
+}
+
+/// long running builtins should call this once in a while, to stop when asked
+pub fn checkpoint() -> Result<(), mlua::Error> {
+	RUNNING.with(|r| match r.borrow().as_ref().and_then(|b| b.check()) {
+		Some(reason) => Err(mlua::Error::RuntimeError(reason.into())),
+		None => Ok(()),
+	})
+}
+
+/// run an evaluation within given limits. lua code gets stopped by the hook, while
+/// evaluations waiting on something get dropped. Err carries why it got stopped
+pub async fn limited<T>(limits: &Limits, fut: impl Future<Output = T>) -> Result<T, &'static str> {
+	watch(limits, fut, false).await
+}
+
+/// same as limited, but timeout and instructions count from each time lua code gets to run
+/// again: background tasks may wait for as long as they like, just not hog their state
+pub async fn sliced<T>(limits: &Limits, fut: impl Future<Output = T>) -> Result<T, &'static str> {
+	watch(limits, fut, true).await
+}
+
+async fn watch<T>(limits: &Limits, fut: impl Future<Output = T>, sliced: bool) -> Result<T, &'static str> {
+	let _armed = limits.interrupt.arm();
+	let deadline = limits.timeout.map(|t| Instant::now() + t);
+	let budget = Rc::new(Budget {
+		interrupt: limits.interrupt.clone(),
+		timeout: limits.timeout,
+		sliced,
+		deadline: Cell::new(deadline),
+		instructions: limits.instructions,
+		spent: Cell::new(0),
+		stopped: Cell::new(None),
+	});
+
+	let res = tokio::select! {
+		res = Tracked { inner: Box::pin(fut), budget: budget.clone() } => Ok(res),
+		_ = limits.interrupt.wait() => Err(INTERRUPTED),
+		_ = sleep_until_deadline(if sliced { None } else { deadline }) => Err(OUT_OF_TIME),
+	};
+
+	match budget.stopped.get() {
+		Some(reason) => Err(reason),
+		None => res,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn limits(ms: u64) -> Limits {
+		Limits { timeout: Some(Duration::from_millis(ms)), ..Default::default() }
+	}
+
+	async fn naps() {
+		for _ in 0..4 {
+			tokio::time::sleep(Duration::from_millis(30)).await;
+		}
+	}
+
+	async fn hog() -> Result<(), mlua::Error> {
+		loop {
+			checkpoint()?;
+		}
+	}
+
+	#[tokio::test]
+	async fn limited_counts_whole_evaluation() {
+		assert_eq!(limited(&limits(80), naps()).await, Err(OUT_OF_TIME));
+		assert!(limited(&limits(80), hog()).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn sliced_lets_waiting_code_run_but_not_hogs() {
+		assert_eq!(sliced(&limits(80), naps()).await, Ok(()));
+		assert_eq!(sliced(&limits(80), hog()).await.err(), Some(OUT_OF_TIME));
+	}
+
+	#[tokio::test]
+	async fn only_evaluations_count_as_such() {
+		assert!(!in_evaluation());
+		assert_eq!(limited(&Limits::default(), async { in_evaluation() }).await, Ok(true));
+		assert_eq!(sliced(&Limits::default(), async { in_evaluation() }).await, Ok(false));
+		assert!(!in_evaluation());
+	}
+
+	#[tokio::test]
+	async fn interrupt_stops_waiting_evaluation() {
+		let limits = Limits::default();
+		let interrupt = limits.interrupt.clone();
+		assert!(!interrupt.trigger());
+		let stop = async {
+			tokio::time::sleep(Duration::from_millis(20)).await;
+			assert!(interrupt.trigger());
+		};
+		let (res, _) = tokio::join!(sliced(&limits, std::future::pending::<()>()), stop);
+		assert_eq!(res, Err(INTERRUPTED));
+	}
+}
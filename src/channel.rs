@@ -1,103 +1,586 @@
-use mlua::Lua;
-use tokio::{sync::{mpsc, broadcast}, net::{TcpListener, TcpStream}, io::{AsyncWriteExt, AsyncReadExt}};
-use tracing::{debug, error, warn};
+use std::{os::{linux::net::SocketAddrExt, unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt}}, path::{Path, PathBuf}, time::Duration, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}};
 
-use crate::{repl::{LuaRepl, VERSIONTEXT}, tools::register_builtin_fn};
+use tokio::{sync::{mpsc, Semaphore, OwnedSemaphorePermit}, net::{TcpListener, UnixListener, TcpStream, UnixStream}, io::{AsyncWriteExt, AsyncReadExt, AsyncRead, AsyncWrite, AsyncBufReadExt, BufReader}, task::{JoinHandle, JoinError}};
+use tokio_rustls::{TlsAcceptor, TlsConnector, rustls::ServerName};
+use tracing::{debug, error, warn, info};
 
-pub struct ControlChannel {
-	addr: String,
-	sink: mpsc::Receiver<String>,
-	source: broadcast::Sender<String>,
+use crate::{repl::{LuaRepl, VERSIONTEXT, ETX}, budget::Limits, rpc::RpcSession, auth::{challenge, peer_allowed, receive_line}, tls::{acceptor, connector}, endpoint::Endpoint, config::Config, console::{Console, Output}, helpers::sleep_until_deadline, history::History, state::{LuaState, LuaStateHandle, MAIN_STATE, shared}, transcript::Transcript};
+
+const MIN_BACKOFF : Duration = Duration::from_secs(1);
+const MAX_BACKOFF : Duration = Duration::from_secs(60);
+/// peers get this long to complete a tls handshake
+const TLS_TIMEOUT : Duration = Duration::from_secs(10);
+
+/// socket files we created, with the pid which created them, to remove on exit
+static SOCKET_FILES: Mutex<Vec<(u32, PathBuf)>> = Mutex::new(Vec::new());
+
+/// what clients speak over a control channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+	/// interactive character based repl, for humans with netcat
+	Repl,
+	/// line based json requests, for tooling
+	Rpc,
 }
 
-pub struct ControlChannelHandle {
-	pub sink: mpsc::Sender<String>,
-	pub source: broadcast::Receiver<String>,
+pub struct ControlChannel {
+	addr: Endpoint,
+	protocol: Protocol,
+	cfg: Arc<Config>,
+	tls: Option<TlsAcceptor>,
+	slots: Option<Arc<Semaphore>>,
+	counter: AtomicUsize,
 }
 
 impl ControlChannel {
-	pub fn run(addr: String) -> ControlChannelHandle {
-		let (sink_tx, sink_rx) = mpsc::channel(64);
-		let (source_tx, source_rx) = broadcast::channel(64);
+	pub fn run(addr: Endpoint, protocol: Protocol, cfg: &Config) -> JoinHandle<()> {
 		let mut chan = ControlChannel {
 			addr,
-			sink: sink_rx,
-			source: source_tx,
+			protocol,
+			cfg: Arc::new(cfg.clone()),
+			tls: None,
+			slots: cfg.max_sessions.map(|n| Arc::new(Semaphore::new(n))),
+			counter: AtomicUsize::new(0),
+		};
+
+		tokio::spawn(async move { chan.work().await })
+	}
+
+	/// rather than listening, dial out to a controller and serve a shell over that
+	/// connection. dials again when the session ends, backing off on failures
+	pub fn connect(addr: Endpoint, cfg: &Config) -> JoinHandle<()> {
+		let chan = ControlChannel {
+			addr,
+			protocol: Protocol::Repl,
+			cfg: Arc::new(cfg.clone()),
+			tls: None,
+			slots: cfg.max_sessions.map(|n| Arc::new(Semaphore::new(n))),
+			counter: AtomicUsize::new(0),
 		};
 
-		tokio::spawn(async move { chan.work().await });
+		tokio::spawn(async move { chan.dial().await })
+	}
+
+	async fn dial(&self) {
+		let tls = match connector(&self.cfg) {
+			Ok(tls) => tls,
+			Err(e) => {
+				error!("could not prepare tls, refusing to dial {}: {}", self.addr, e);
+				return;
+			},
+		};
+		let mut backoff = MIN_BACKOFF;
+		loop {
+			match self.open(tls.as_ref()).await {
+				Ok(session) => {
+					debug!("session with controller {} ended", self.addr);
+					backoff = MIN_BACKOFF;
+					if let Err(e) = session {
+						error!("session with controller {} crashed: {}", self.addr, e);
+					}
+				},
+				Err(e) => {
+					debug!("could not reach controller {}, retrying in {}s: {}", self.addr, backoff.as_secs(), e);
+					tokio::time::sleep(backoff).await;
+					backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+					continue;
+				},
+			}
+			tokio::time::sleep(MIN_BACKOFF).await;
+		}
+	}
+
+	/// connect to controller and serve it, resolving when the session ends
+	async fn open(&self, tls: Option<&TlsConnector>) -> std::io::Result<Result<(), JoinError>> {
+		let session = match &self.addr {
+			Endpoint::Tcp(addr) => {
+				let stream = TcpStream::connect(addr).await?;
+				let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| addr.clone());
+				self.secure(tls, stream, peer).await?
+			},
+			Endpoint::Unix(path) => self.secure(tls, UnixStream::connect(path).await?, path.display().to_string()).await?,
+			Endpoint::Abstract(name) => {
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+				let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+				stream.set_nonblocking(true)?;
+				self.secure(tls, UnixStream::from_std(stream)?, format!("@{}", name)).await?
+			},
+		};
+		info!("connected to controller {}", self.addr);
+		Ok(session.await)
+	}
 
-		ControlChannelHandle { sink: sink_tx, source: source_rx }
+	/// serve a stream we dialed, wrapping it in tls first if configured
+	async fn secure<S>(&self, tls: Option<&TlsConnector>, stream: S, peer: String) -> std::io::Result<JoinHandle<()>>
+	where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+		let tls = match tls {
+			None => return Ok(self.serve(stream, peer)),
+			Some(tls) => tls,
+		};
+		// controller certificate must match the host we dialed, unix sockets have none
+		let host = match &self.addr {
+			Endpoint::Tcp(addr) => addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host),
+			_ => "localhost",
+		};
+		let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid server name '{}': {}", host, e)))?;
+		let stream = tokio::time::timeout(TLS_TIMEOUT, tls.connect(name, stream)).await
+			.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "tls handshake timed out"))??;
+		Ok(self.serve(stream, peer))
 	}
 
 	async fn work(&mut self) {
-		match TcpListener::bind(&self.addr).await {
-			Ok(listener) => {
-				loop {
-					match listener.accept().await {
-						Ok((stream, addr)) => {
-							debug!("accepted connection from {}, serving shell", addr);
-							self.process(stream).await;
+		self.tls = match acceptor(&self.cfg) {
+			Ok(tls) => tls,
+			Err(e) => {
+				error!("could not prepare tls, refusing to listen on {}: {}", self.addr, e);
+				return;
+			},
+		};
+
+		match self.addr.clone() {
+			Endpoint::Tcp(addr) => match TcpListener::bind(&addr).await {
+				Ok(listener) => {
+					info!("listening on {}{}", self.addr, if self.tls.is_some() { " (tls)" } else { "" });
+					loop {
+						match listener.accept().await {
+							Ok((stream, addr)) => { self.serve(stream, addr.to_string()); },
+							Err(e) => error!("could not accept connection: {}", e),
 						}
-						Err(e) => error!("could not accept connection: {}", e),
 					}
+				},
+				Err(e) => error!("could not bind on {} : {}", self.addr, e),
+			},
+			Endpoint::Unix(_) | Endpoint::Abstract(_) => match self.bind_unix() {
+				Ok(listener) => {
+					info!("listening on {}{}", self.addr, if self.tls.is_some() { " (tls)" } else { "" });
+					loop {
+						match listener.accept().await {
+							Ok((stream, addr)) => {
+								let peer = format!("{:?}", addr);
+								match stream.peer_cred() {
+									Ok(cred) if peer_allowed(&cred, &self.cfg.allowed_uids, &self.cfg.allowed_gids) => {
+										self.serve(stream, format!("{} (uid {} gid {})", peer, cred.uid(), cred.gid()));
+									},
+									Ok(cred) => warn!(
+										"rejecting connection from {}: uid {} gid {} (pid {:?}) not allowed",
+										peer, cred.uid(), cred.gid(), cred.pid()
+									),
+									Err(e) => warn!("rejecting connection from {}: could not get peer credentials: {}", peer, e),
+								}
+							},
+							Err(e) => error!("could not accept connection: {}", e),
+						}
+					}
+				},
+				Err(e) => error!("could not bind on {} : {}", self.addr, e),
+			},
+		}
+	}
+
+	fn bind_unix(&self) -> std::io::Result<UnixListener> {
+		match &self.addr {
+			Endpoint::Unix(path) => {
+				if let Ok(meta) = std::fs::symlink_metadata(path) {
+					if !meta.file_type().is_socket() {
+						return Err(std::io::Error::new(
+							std::io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())
+						));
+					}
+					// probably left behind by a previous process with same pid
+					warn!("removing stale socket file {}", path.display());
+					std::fs::remove_file(path)?;
 				}
+				let listener = bind_private(path)?;
+				SOCKET_FILES.lock().expect("socket files lock is poisoned").push((std::process::id(), path.clone()));
+				Ok(listener)
 			},
-			Err(e) => error!("could not bind on {} : {}", self.addr, e),
+			Endpoint::Abstract(name) => {
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+				let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+				listener.set_nonblocking(true)?;
+				UnixListener::from_std(listener)
+			},
+			Endpoint::Tcp(addr) => Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput, format!("{} is not a unix socket", addr)
+			)),
 		}
 	}
 
-	async fn process(&mut self, mut stream: TcpStream) {
-		let mut lua = Lua::new();
-		let mut repl = LuaRepl::new(self.source.clone().into());
+	/// every connection gets its own task, so sessions don't block each other
+	fn serve<S>(&self, stream: S, peer: String) -> JoinHandle<()>
+	where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+		let id = self.counter.fetch_add(1, Ordering::Relaxed);
+		debug!("serving shell #{} to {}", id, peer);
+		let session = Session { id, peer, protocol: self.protocol, cfg: self.cfg.clone(), slots: self.slots.clone() };
+		match self.tls.clone() {
+			None => tokio::spawn(async move { session.process(stream).await }),
+			Some(tls) => tokio::spawn(async move {
+				match tokio::time::timeout(TLS_TIMEOUT, tls.accept(stream)).await {
+					Ok(Ok(stream)) => session.process(stream).await,
+					Ok(Err(e)) => warn!("tls handshake with {} failed: {}", session.peer, e),
+					Err(_) => warn!("tls handshake with {} timed out", session.peer),
+				}
+			}),
+		}
+	}
+}
 
-		let intro_text = format!(
-			"{} inside process #{}\n@> ",
-			VERSIONTEXT, std::process::id()
-		);
+/// only our own user should be able to reach the repl: bind inside a private directory,
+/// restrict the socket and only then move it where it's expected. the umask is shared
+/// with the host process, so it's better left alone
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+	let name = path.file_name()
+		.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid socket path {}", path.display())))?;
+	let dir = path.with_file_name(format!(".cordy-{}-{}", std::process::id(), name.to_string_lossy()));
+	std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+	let tmp = dir.join(name);
+	let res = UnixListener::bind(&tmp).and_then(|listener| {
+		std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+		std::fs::rename(&tmp, path)?;
+		Ok(listener)
+	});
+	if res.is_err() {
+		let _ = std::fs::remove_file(&tmp);
+	}
+	if let Err(e) = std::fs::remove_dir(&dir) {
+		warn!("could not remove temporary directory {}: {}", dir.display(), e);
+	}
+	res
+}
 
-		if let Err(e) = repl.write(intro_text) {
-			warn!("could not display version on repl: {}", e);
+/// remove socket files created by this process, forked children leave them alone
+pub fn remove_socket_files() {
+	let pid = std::process::id();
+	if let Ok(files) = SOCKET_FILES.lock() {
+		for (_, path) in files.iter().filter(|(owner, _)| *owner == pid) {
+			let _ = std::fs::remove_file(path);
 		}
+	}
+}
 
-		if let Err(e) = register_builtin_fn(&mut lua, self.source.clone()) {
-			error!("could not prepare runtime environment: {}", e);
+struct Session {
+	id: usize,
+	peer: String,
+	protocol: Protocol,
+	cfg: Arc<Config>,
+	/// taken once authenticated, so idle unauthenticated peers can't lock operators out
+	slots: Option<Arc<Semaphore>>,
+}
+
+impl Session {
+	/// take a session slot, or tell the peer there are none left
+	async fn admit<S>(&self, stream: &mut S, refusal: &str) -> Result<Option<OwnedSemaphorePermit>, ()>
+	where S: AsyncWrite + Unpin {
+		let slots = match &self.slots {
+			None => return Ok(None),
+			Some(slots) => slots.clone(),
+		};
+		match slots.try_acquire_owned() {
+			Ok(permit) => Ok(Some(permit)),
+			Err(_) => {
+				warn!("rejecting connection from {}: too many sessions", self.peer);
+				if let Err(e) = stream.write_all(refusal.as_bytes()).await {
+					debug!("could not notify rejection to {}: {}", self.peer, e);
+				}
+				Err(())
+			},
 		}
+	}
 
-		loop {
-			tokio::select! {
+	async fn process<S>(self, stream: S) where S: AsyncRead + AsyncWrite + Unpin {
+		match self.protocol {
+			Protocol::Repl => self.repl(stream).await,
+			Protocol::Rpc => self.rpc(stream).await,
+		}
+	}
+
+	fn state(&self, console: &Console) -> std::io::Result<LuaStateHandle> {
+		if self.cfg.shared_state {
+			shared(MAIN_STATE)
+		} else {
+			LuaState::run(format!("#{}", self.id), Some(console.clone()))
+		}
+	}
+
+	fn transcript(&self) -> Option<Transcript> {
+		self.cfg.transcript.as_ref().and_then(|path| Transcript::open(path, &format!("#{}", self.id), &self.peer))
+	}
 
-				rx = stream.read_u8() => match rx { // TODO should be cancelable, but is it really?
+	async fn repl<S>(self, mut stream: S) where S: AsyncRead + AsyncWrite + Unpin {
+		if let Some(token) = &self.cfg.token {
+			match challenge(&mut stream, token).await {
+				Ok(true) => debug!("shell #{} authenticated", self.id),
+				Ok(false) => {
+					warn!("authentication failed for {}, dropping shell #{}", self.peer, self.id);
+					return;
+				},
+				Err(e) => {
+					warn!("authentication aborted by {}: {}", self.peer, e);
+					return;
+				},
+			}
+		}
+		let _permit = match self.admit(&mut stream, "! too many sessions, try again later\n").await {
+			Ok(permit) => permit,
+			Err(()) => return,
+		};
+
+		let (console_tx, mut console_rx) = mpsc::unbounded_channel();
+		let console = Console::from(console_tx);
+		let state = match self.state(&console) {
+			Ok(s) => s,
+			Err(e) => {
+				error!("could not prepare runtime environment: {}", e);
+				if let Err(e) = stream.write_all(format!("! could not prepare lua state: {}\n", e).as_bytes()).await {
+					warn!("could not notify failure to remote console: {}", e);
+				}
+				return;
+			},
+		};
+		debug!("shell #{} running on state '{}'", self.id, state.name());
+		let limits = Limits::new(&self.cfg);
+		let interrupt = limits.interrupt.clone();
+		let history = History::open(self.cfg.history_file.clone());
+		let mut repl = LuaRepl::new(console, state, history, limits, self.transcript());
+
+		let intro_text = format!("{} inside process #{}\n", VERSIONTEXT, std::process::id());
+
+		if let Err(e) = repl.write(intro_text).and_then(|_| repl.prompt()) {
+			warn!("could not display version on repl: {}", e);
+		}
+
+		let idle_timeout = self.cfg.idle_timeout();
+		let mut deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+		let (mut rx, mut tx) = tokio::io::split(stream);
+		let (input_tx, mut input_rx) = mpsc::unbounded_channel::<u8>();
+
+		// keep reading while an evaluation runs, so it can be interrupted
+		let reader = async move {
+			loop {
+				match rx.read_u8().await {
 					Ok(c) => {
-						if !c.is_ascii() {
-							debug!("character '{}' is not ascii", c);
-							break;
+						if c == ETX as u8 && interrupt.trigger() {
+							continue;
 						}
-						let ch : char = c as char;
-						if let Err(e) = repl.evaluate(&lua, ch) {
-							error!("could not evaluate input '{}' : {}", repl.buffer(), e);
+						if input_tx.send(c).is_err() {
+							break;
 						}
 					},
 					Err(e) => {
 						debug!("lost connection: {}", e);
 						break;
-					}
-				},
+					},
+				}
+			}
+		};
 
-				tx = self.sink.recv() => match tx {
-					Some(txt) => {
-						if let Err(e) = stream.write_all(txt.as_bytes()).await {
-							error!("could not send output to remote console: {}", e);
+		let processor = async {
+			loop {
+				tokio::select! {
+
+					rx = input_rx.recv() => match rx {
+						Some(c) => {
+							deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+							if let Err(e) = repl.feed(c).await {
+								error!("could not evaluate input '{}' : {}", repl.buffer(), e);
+							}
+							if repl.closed() {
+								while let Ok(out) = console_rx.try_recv() {
+									if let Output::Text(txt) = &out { repl.record(txt); }
+									let txt = out.into_string();
+									let txt = if repl.raw() { txt.replace('\n', "\r\n") } else { txt };
+									if tx.write_all(txt.as_bytes()).await.is_err() { break; }
+								}
+								debug!("shell #{} asked to quit", self.id);
+								break;
+							}
+						},
+						None => break,
+					},
+
+					out = console_rx.recv() => match out {
+						Some(out) => {
+							if let Output::Text(txt) = &out {
+								repl.record(txt);
+							}
+							let txt = out.into_string();
+							// raw terminals won't return carriage on their own
+							let txt = if repl.raw() { txt.replace('\n', "\r\n") } else { txt };
+							if let Err(e) = tx.write_all(txt.as_bytes()).await {
+								error!("could not send output to remote console: {}", e);
+								break;
+							}
+						}
+						None => {
+							error!("console closed, exiting processor");
 							break;
 						}
-					}
-					None => {
-						error!("command sink closed, exiting processor");
+					},
+
+					_ = sleep_until_deadline(deadline) => {
+						debug!("session idle for too long, disconnecting");
+						if let Err(e) = tx.write_all(b"\n! idle timeout reached, bye\n").await {
+							warn!("could not notify idle timeout to remote console: {}", e);
+						}
+						break;
+					},
+
+				}
+			}
+		};
+
+		tokio::select! {
+			_ = reader => {},
+			_ = processor => {},
+		}
+
+		debug!("shell #{} closed", self.id);
+	}
+
+	async fn rpc<S>(self, mut stream: S) where S: AsyncRead + AsyncWrite + Unpin {
+		if let Err(e) = stream.write_all(RpcSession::hello(self.cfg.token.is_some()).as_bytes()).await {
+			warn!("could not greet rpc client {}: {}", self.peer, e);
+			return;
+		}
+
+		if let Some(token) = &self.cfg.token {
+			let (reply, ok) = match receive_line(&mut stream).await {
+				Ok(line) => RpcSession::authenticate(line.as_deref().unwrap_or(""), token),
+				Err(e) => {
+					warn!("authentication aborted by {}: {}", self.peer, e);
+					return;
+				},
+			};
+			if let Err(e) = stream.write_all(reply.as_bytes()).await {
+				warn!("could not send authentication result to {}: {}", self.peer, e);
+				return;
+			}
+			if !ok {
+				warn!("authentication failed for {}, dropping rpc #{}", self.peer, self.id);
+				return;
+			}
+		}
+		let _permit = match self.admit(&mut stream, &RpcSession::refusal("too many sessions, try again later")).await {
+			Ok(permit) => permit,
+			Err(()) => return,
+		};
+
+		let (console_tx, mut console_rx) = mpsc::unbounded_channel();
+		let console = Console::from(console_tx);
+		let mut rpc = match self.state(&console) {
+			Ok(state) => RpcSession::new(state, Limits::new(&self.cfg), self.transcript()),
+			Err(e) => {
+				error!("could not prepare runtime environment: {}", e);
+				return;
+			},
+		};
+
+		let idle_timeout = self.cfg.idle_timeout();
+		let mut deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+		let (rx, mut tx) = tokio::io::split(stream);
+		let mut lines = BufReader::new(rx).lines();
+
+		loop {
+			tokio::select! {
+
+				line = lines.next_line() => match line {
+					Ok(Some(line)) => {
+						deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+						if line.trim().is_empty() { continue; }
+						match rpc.handle(&line).await {
+							Ok(res) => if let Err(e) = tx.write_all(res.as_bytes()).await {
+								error!("could not send response to rpc client: {}", e);
+								break;
+							},
+							Err(e) => {
+								error!("could not process rpc request: {}", e);
+								break;
+							},
+						}
+					},
+					Ok(None) => break,
+					Err(e) => {
+						debug!("lost connection: {}", e);
+						break;
+					},
+				},
+
+				out = console_rx.recv() => if let Some(out) = out {
+					if let Err(e) = tx.write_all(rpc.event(out.into_string()).as_bytes()).await {
+						error!("could not send event to rpc client: {}", e);
 						break;
 					}
 				},
 
+				_ = sleep_until_deadline(deadline) => {
+					debug!("rpc session idle for too long, disconnecting");
+					break;
+				},
+
 			}
 		}
+
+		drop(console);
+		debug!("rpc #{} closed", self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn session(slots: Option<Arc<Semaphore>>) -> Session {
+		Session { id: 0, peer: "test".into(), protocol: Protocol::Repl, cfg: Arc::new(Config::default()), slots }
+	}
+
+	fn channel(path: &Path) -> ControlChannel {
+		ControlChannel {
+			addr: Endpoint::Unix(path.into()),
+			protocol: Protocol::Repl,
+			cfg: Arc::new(Config::default()),
+			tls: None,
+			slots: None,
+			counter: AtomicUsize::new(0),
+		}
+	}
+
+	#[tokio::test]
+	async fn unix_sockets_are_private_and_only_replace_sockets() {
+		let dir = std::env::temp_dir().join(format!("cordy-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("repl.sock");
+		let _ = std::fs::remove_file(&path);
+
+		let first = channel(&path).bind_unix().unwrap();
+		let meta = std::fs::symlink_metadata(&path).unwrap();
+		assert!(meta.file_type().is_socket());
+		assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+		drop(first);
+		channel(&path).bind_unix().expect("stale sockets get replaced");
+
+		let file = dir.join("precious");
+		std::fs::write(&file, "data").unwrap();
+		assert!(channel(&file).bind_unix().is_err());
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "data");
+
+		remove_socket_files();
+		assert!(!path.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn sessions_are_admitted_while_slots_last() {
+		let slots = Arc::new(Semaphore::new(1));
+		let (mut near, mut far) = tokio::io::duplex(64);
+		let first = session(Some(slots.clone())).admit(&mut near, "full\n").await;
+		assert!(matches!(first, Ok(Some(_))));
+		assert!(session(Some(slots.clone())).admit(&mut near, "full\n").await.is_err());
+		let mut buf = [0u8; 5];
+		far.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"full\n");
+		drop(first);
+		assert!(matches!(session(Some(slots)).admit(&mut near, "full\n").await, Ok(Some(_))));
+		assert!(matches!(session(None).admit(&mut near, "full\n").await, Ok(None)));
 	}
 }
@@ -0,0 +1,160 @@
+use std::collections::BTreeSet;
+
+use mlua::{Lua, MetaMethod, Table, Value};
+
+use crate::helpers::{KEYWORDS, is_identifier};
+
+/// how many __index tables are followed looking for fields
+const MAX_INDEX_DEPTH : usize = 8;
+
+/// possible names completing last segment of {word}, like `string.fo` or `obj:me`.
+/// only raw fields and __index tables are inspected, so no lua code gets run
+pub fn completions(lua: &Lua, word: &str) -> Vec<String> {
+	let (base, method, partial) = match word.rfind(['.', ':']) {
+		Some(pos) => (&word[..pos], word[pos..].starts_with(':'), &word[pos + 1..]),
+		None => ("", false, word),
+	};
+
+	let mut names = BTreeSet::new();
+	if base.is_empty() {
+		if word.contains(['.', ':']) {
+			return vec![];
+		}
+		collect(Value::Table(lua.globals()), false, &mut names);
+		names.extend(KEYWORDS.iter().map(|k| k.to_string()));
+	} else {
+		match resolve(lua, base) {
+			Some(val) => collect(val, method, &mut names),
+			None => return vec![],
+		}
+	}
+
+	names.into_iter().filter(|n| n.starts_with(partial)).collect()
+}
+
+/// follow a dotted path starting from globals
+fn resolve<'lua>(lua: &'lua Lua, path: &str) -> Option<Value<'lua>> {
+	let mut val = Value::Table(lua.globals());
+	for segment in path.split(['.', ':']) {
+		val = field(val, segment)?;
+	}
+	Some(val)
+}
+
+fn field<'lua>(val: Value<'lua>, key: &str) -> Option<Value<'lua>> {
+	let mut table = index_table(val)?;
+	for _ in 0..MAX_INDEX_DEPTH {
+		match table.raw_get::<_, Value>(key).ok()? {
+			Value::Nil => table = parent(&table)?,
+			found => return Some(found),
+		}
+	}
+	None
+}
+
+/// table holding fields of {val}: itself for tables, __index for userdata
+fn index_table(val: Value) -> Option<Table> {
+	match val {
+		Value::Table(t) => Some(t),
+		Value::UserData(ud) => match ud.get_metatable().ok()?.get::<_, Value>(MetaMethod::Index).ok()? {
+			Value::Table(t) => Some(t),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+/// identifier keys of {val} and of its __index chain. with {methods} only functions are kept
+fn collect(val: Value, methods: bool, names: &mut BTreeSet<String>) {
+	let mut table = match index_table(val) {
+		Some(t) => t,
+		None => return,
+	};
+	for _ in 0..MAX_INDEX_DEPTH {
+		for (key, val) in table.clone().pairs::<Value, Value>().flatten() {
+			if methods && !matches!(val, Value::Function(_)) {
+				continue;
+			}
+			if let Value::String(key) = key {
+				if let Ok(key) = key.to_str() {
+					if is_identifier(key) {
+						names.insert(key.to_string());
+					}
+				}
+			}
+		}
+		table = match parent(&table) {
+			Some(t) => t,
+			None => return,
+		};
+	}
+}
+
+/// next table in the __index chain, if __index is a table
+fn parent<'lua>(table: &Table<'lua>) -> Option<Table<'lua>> {
+	match table.get_metatable()?.raw_get::<_, Value>("__index").ok()? {
+		Value::Table(t) => Some(t),
+		_ => None,
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::{Lua, UserData, UserDataMethods};
+
+	use super::*;
+
+	struct Probe;
+
+	impl UserData for Probe {
+		fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+			methods.add_method("poke", |_, _, ()| Ok(()));
+			methods.add_method("peek", |_, _, ()| Ok(()));
+		}
+	}
+
+	fn lua() -> Lua {
+		let lua = Lua::new();
+		lua.load(r#"
+			counter = 1
+			config = { verbose = true, vendor = { name = "x" }, ["end"] = 1, ["not valid"] = 2 }
+			obj = setmetatable({ field = 1, frob = function() end }, { __index = { base = function() end } })
+		"#).exec().unwrap();
+		lua.globals().set("probe", Probe).unwrap();
+		lua
+	}
+
+	#[test]
+	fn globals_and_keywords() {
+		let lua = lua();
+		assert_eq!(completions(&lua, "coun"), vec!["counter"]);
+		assert_eq!(completions(&lua, "wh"), vec!["while"]);
+		assert!(completions(&lua, "").contains(&"config".to_string()));
+		assert!(completions(&lua, "nothing_like_this").is_empty());
+	}
+
+	#[test]
+	fn dotted_paths() {
+		let lua = lua();
+		assert_eq!(completions(&lua, "config.ve"), vec!["vendor", "verbose"]);
+		assert_eq!(completions(&lua, "config.vendor.n"), vec!["name"]);
+		assert_eq!(completions(&lua, "string.up"), vec!["upper"]);
+		assert!(completions(&lua, "config.missing.").is_empty());
+		assert!(completions(&lua, ".co").is_empty());
+	}
+
+	#[test]
+	fn keywords_and_odd_keys_are_not_fields() {
+		let lua = lua();
+		assert_eq!(completions(&lua, "config."), vec!["vendor", "verbose"]);
+	}
+
+	#[test]
+	fn methods_follow_index_tables() {
+		let lua = lua();
+		assert_eq!(completions(&lua, "obj:"), vec!["base", "frob"]);
+		assert_eq!(completions(&lua, "obj."), vec!["base", "field", "frob"]);
+		assert_eq!(completions(&lua, "probe:p"), vec!["peek", "poke"]);
+	}
+}
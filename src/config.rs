@@ -0,0 +1,255 @@
+use std::{fmt::Display, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Deserializer};
+use tracing::level_filters::LevelFilter;
+
+use crate::endpoint::Endpoint;
+
+pub const ENV_PREFIX : &str = "CORDY_";
+
+/// runtime options, read when the shared object gets loaded.
+/// values come from the file pointed by `CORDY_CONFIG` (toml, or json if it ends
+/// in `.json`) and each can be overridden with its own `CORDY_<FIELD>` env var
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// set to `none` to not open any control channel
+	#[serde(deserialize_with = "parse_endpoint")]
+	pub listen: Option<Endpoint>,
+	/// serve the json rpc protocol on this address
+	#[serde(deserialize_with = "parse_endpoint")]
+	pub rpc_listen: Option<Endpoint>,
+	/// dial out to a controller at this address, rather than (or on top of) listening
+	#[serde(deserialize_with = "parse_endpoint")]
+	pub connect: Option<Endpoint>,
+	#[serde(deserialize_with = "parse")]
+	pub log_level: LevelFilter,
+	pub log_file: Option<PathBuf>,
+	/// seconds of client inactivity before a session gets dropped
+	pub idle_timeout: Option<u64>,
+	/// attach every session to the long lived main state, rather than a fresh one
+	pub shared_state: bool,
+	/// how many sessions can be connected at the same time
+	pub max_sessions: Option<usize>,
+	/// lua script, or directory of scripts, to run as soon as we get loaded
+	pub autorun: Option<PathBuf>,
+	/// pre-shared token clients must send before getting a repl
+	pub token: Option<String>,
+	/// unix socket peers must run as one of these users
+	pub allowed_uids: Option<Vec<u32>>,
+	/// unix socket peers must run with one of these groups
+	pub allowed_gids: Option<Vec<u32>>,
+	/// pem certificate chain, serve the control channel over tls when set
+	pub tls_cert: Option<PathBuf>,
+	/// pem private key matching tls_cert
+	pub tls_key: Option<PathBuf>,
+	/// pem ca certificates, clients must present a certificate signed by one of these
+	pub tls_client_ca: Option<PathBuf>,
+	/// keep repl history in this file, so it survives across processes
+	pub history_file: Option<PathBuf>,
+	/// seconds a single evaluation may run before being stopped
+	pub eval_timeout: Option<u64>,
+	/// vm instructions a single evaluation may run before being stopped
+	pub eval_instructions: Option<u64>,
+	/// let ^C stop lua code while it runs, which costs the jit compiler
+	pub interrupts: bool,
+	/// record every session to this file, or to one file per session if it's a directory
+	pub transcript: Option<PathBuf>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			listen: Some(Endpoint::default()),
+			rpc_listen: None,
+			connect: None,
+			log_level: LevelFilter::DEBUG,
+			log_file: None,
+			idle_timeout: None,
+			shared_state: false,
+			max_sessions: None,
+			autorun: None,
+			token: None,
+			allowed_uids: None,
+			allowed_gids: None,
+			tls_cert: None,
+			tls_key: None,
+			tls_client_ca: None,
+			history_file: None,
+			eval_timeout: None,
+			eval_instructions: None,
+			interrupts: true,
+			transcript: None,
+		}
+	}
+}
+
+impl Config {
+	pub fn load() -> Result<Self, String> {
+		let mut cfg = match std::env::var_os(format!("{}CONFIG", ENV_PREFIX)) {
+			Some(path) => Config::from_file(Path::new(&path))?,
+			None => Config::default(),
+		};
+
+		if let Some(listen) = env::<String>("LISTEN")? { cfg.listen = endpoint_spec(&listen)?; }
+		if let Some(rpc) = env::<String>("RPC_LISTEN")? { cfg.rpc_listen = endpoint_spec(&rpc)?; }
+		if let Some(connect) = env::<String>("CONNECT")? { cfg.connect = endpoint_spec(&connect)?; }
+		if let Some(level) = env("LOG_LEVEL")? { cfg.log_level = level; }
+		if let Some(file) = env("LOG_FILE")? { cfg.log_file = Some(file); }
+		if let Some(secs) = env("IDLE_TIMEOUT")? { cfg.idle_timeout = Some(secs); }
+		if let Some(shared) = env("SHARED_STATE")? { cfg.shared_state = shared; }
+		if let Some(n) = env("MAX_SESSIONS")? { cfg.max_sessions = Some(n); }
+		if let Some(path) = env("AUTORUN")? { cfg.autorun = Some(path); }
+		if let Some(token) = env("TOKEN")? { cfg.token = Some(token); }
+		if let Some(uids) = env_ids("ALLOWED_UIDS")? { cfg.allowed_uids = Some(uids); }
+		if let Some(gids) = env_ids("ALLOWED_GIDS")? { cfg.allowed_gids = Some(gids); }
+		if let Some(path) = env("TLS_CERT")? { cfg.tls_cert = Some(path); }
+		if let Some(path) = env("TLS_KEY")? { cfg.tls_key = Some(path); }
+		if let Some(path) = env("TLS_CLIENT_CA")? { cfg.tls_client_ca = Some(path); }
+		if let Some(path) = env("HISTORY_FILE")? { cfg.history_file = Some(path); }
+		if let Some(secs) = env("EVAL_TIMEOUT")? { cfg.eval_timeout = Some(secs); }
+		if let Some(n) = env("EVAL_INSTRUCTIONS")? { cfg.eval_instructions = Some(n); }
+		if let Some(on) = env("INTERRUPTS")? { cfg.interrupts = on; }
+		if let Some(path) = env("TRANSCRIPT")? { cfg.transcript = Some(path); }
+
+		Ok(cfg)
+	}
+
+	pub fn from_file(path: &Path) -> Result<Self, String> {
+		let txt = std::fs::read_to_string(path)
+			.map_err(|e| format!("could not read config file {}: {}", path.display(), e))?;
+		match path.extension().and_then(|x| x.to_str()) {
+			Some("json") => serde_json::from_str(&txt)
+				.map_err(|e| format!("invalid json config {}: {}", path.display(), e)),
+			_ => toml::from_str(&txt)
+				.map_err(|e| format!("invalid toml config {}: {}", path.display(), e)),
+		}
+	}
+
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		self.idle_timeout.map(Duration::from_secs)
+	}
+
+	pub fn eval_timeout(&self) -> Option<Duration> {
+		self.eval_timeout.map(Duration::from_secs)
+	}
+}
+
+/// read and parse a `CORDY_` prefixed env var, empty vars are treated as unset
+fn env<T>(name: &str) -> Result<Option<T>, String> where T: FromStr, T::Err: Display {
+	let key = format!("{}{}", ENV_PREFIX, name);
+	match std::env::var(&key) {
+		Ok(val) if val.is_empty() => Ok(None),
+		Ok(val) => val.parse().map(Some).map_err(|e| format!("invalid value for {}: {}", key, e)),
+		Err(std::env::VarError::NotPresent) => Ok(None),
+		Err(e) => Err(format!("invalid value for {}: {}", key, e)),
+	}
+}
+
+/// comma separated list of numeric ids
+fn env_ids(name: &str) -> Result<Option<Vec<u32>>, String> {
+	match env::<String>(name)? {
+		None => Ok(None),
+		Some(list) => list.split(',')
+			.map(|x| x.trim().parse::<u32>().map_err(|e| format!("invalid id '{}' in {}{}: {}", x, ENV_PREFIX, name, e)))
+			.collect::<Result<Vec<u32>, String>>()
+			.map(Some),
+	}
+}
+
+fn endpoint_spec(spec: &str) -> Result<Option<Endpoint>, String> {
+	match spec {
+		"none" => Ok(None),
+		_ => spec.parse().map(Some),
+	}
+}
+
+fn parse_endpoint<'de, D>(de: D) -> Result<Option<Endpoint>, D::Error> where D: Deserializer<'de> {
+	endpoint_spec(&String::deserialize(de)?).map_err(serde::de::Error::custom)
+}
+
+fn parse<'de, D, T>(de: D) -> Result<T, D::Error> where D: Deserializer<'de>, T: FromStr, T::Err: Display {
+	String::deserialize(de)?.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write(name: &str, txt: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("cordy-config-test-{}-{}", std::process::id(), name));
+		std::fs::write(&path, txt).unwrap();
+		path
+	}
+
+	#[test]
+	fn toml_and_json_files() {
+		let toml = write("cfg.toml", r#"
+			listen = "unix:/tmp/cordy.sock"
+			log_level = "warn"
+			max_sessions = 2
+			allowed_uids = [0, 1000]
+			interrupts = false
+		"#);
+		let json = write("cfg.json", r#"{ "listen": "none", "connect": "127.0.0.1:4000", "eval_timeout": 5 }"#);
+		let (from_toml, from_json) = (Config::from_file(&toml), Config::from_file(&json));
+		std::fs::remove_file(toml).unwrap();
+		std::fs::remove_file(json).unwrap();
+
+		let cfg = from_toml.unwrap();
+		assert_eq!(cfg.listen, Some(Endpoint::Unix("/tmp/cordy.sock".into())));
+		assert_eq!(cfg.log_level, LevelFilter::WARN);
+		assert_eq!(cfg.max_sessions, Some(2));
+		assert_eq!(cfg.allowed_uids, Some(vec![0, 1000]));
+		assert!(!cfg.interrupts);
+		assert_eq!(cfg.eval_timeout, None); // untouched fields keep their default
+
+		let cfg = from_json.unwrap();
+		assert_eq!(cfg.listen, None);
+		assert_eq!(cfg.connect, Some(Endpoint::Tcp("127.0.0.1:4000".into())));
+		assert_eq!(cfg.eval_timeout(), Some(Duration::from_secs(5)));
+		assert!(cfg.interrupts);
+	}
+
+	#[test]
+	fn unknown_fields_are_rejected() {
+		let toml = write("typo.toml", "listne = \"none\"\n");
+		let json = write("typo.json", r#"{ "max_session": 1 }"#);
+		let (from_toml, from_json) = (Config::from_file(&toml), Config::from_file(&json));
+		std::fs::remove_file(toml).unwrap();
+		std::fs::remove_file(json).unwrap();
+		assert!(from_toml.unwrap_err().contains("listne"));
+		assert!(from_json.unwrap_err().contains("max_session"));
+	}
+
+	// the only test touching CORDY_ env vars, so tests running in parallel can't see them
+	#[test]
+	fn env_overrides() {
+		let file = write("env.toml", "listen = \"127.0.0.1:5000\"\ntoken = \"from file\"\nmax_sessions = 3\n");
+		std::env::set_var("CORDY_CONFIG", &file);
+		std::env::set_var("CORDY_LISTEN", "none");
+		std::env::set_var("CORDY_RPC_LISTEN", "@cordy-rpc");
+		std::env::set_var("CORDY_TOKEN", "from env");
+		std::env::set_var("CORDY_MAX_SESSIONS", ""); // empty means unset
+		std::env::set_var("CORDY_ALLOWED_GIDS", "10, 20,30");
+		let cfg = Config::load();
+		std::env::set_var("CORDY_ALLOWED_GIDS", "10,wheel");
+		let bad_ids = Config::load();
+		std::env::set_var("CORDY_ALLOWED_GIDS", "");
+		std::env::set_var("CORDY_EVAL_TIMEOUT", "soon");
+		let bad_number = Config::load();
+		for var in ["CONFIG", "LISTEN", "RPC_LISTEN", "TOKEN", "MAX_SESSIONS", "ALLOWED_GIDS", "EVAL_TIMEOUT"] {
+			std::env::remove_var(format!("{}{}", ENV_PREFIX, var));
+		}
+		std::fs::remove_file(file).unwrap();
+
+		let cfg = cfg.unwrap();
+		assert_eq!(cfg.listen, None);
+		assert_eq!(cfg.rpc_listen, Some(Endpoint::Abstract("cordy-rpc".into())));
+		assert_eq!(cfg.token.as_deref(), Some("from env"));
+		assert_eq!(cfg.max_sessions, Some(3));
+		assert_eq!(cfg.allowed_gids, Some(vec![10, 20, 30]));
+		assert!(bad_ids.unwrap_err().contains("invalid id 'wheel' in CORDY_ALLOWED_GIDS"));
+		assert!(bad_number.unwrap_err().contains("CORDY_EVAL_TIMEOUT"));
+	}
+}
@@ -1,11 +1,27 @@
 use mlua::{UserData, Error};
-use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+/// what gets sent to a session
+pub enum Output {
+	/// results, logs and messages
+	Text(String),
+	/// terminal handling, like echo, prompts and line redraws. shown but not recorded
+	Screen(String),
+}
+
+impl Output {
+	pub fn into_string(self) -> String {
+		match self {
+			Output::Text(txt) | Output::Screen(txt) => txt,
+		}
+	}
+}
 
 #[derive(Clone)]
-pub struct Console (broadcast::Sender<String>);
+pub struct Console (mpsc::UnboundedSender<Output>);
 
-impl From::<broadcast::Sender<String>> for Console {
-	fn from(channel: broadcast::Sender<String>) -> Self {
+impl From::<mpsc::UnboundedSender<Output>> for Console {
+	fn from(channel: mpsc::UnboundedSender<Output>) -> Self {
 		Console(channel)
 	}
 }
@@ -13,8 +29,16 @@ impl From::<broadcast::Sender<String>> for Console {
 impl UserData for Console {}
 impl Console {
 	pub fn send(&self, msg: String) -> Result<(), Error> {
-		match self.0.send(msg) {
-			Ok(_n_recv) => Ok(()),
+		self.output(Output::Text(msg))
+	}
+
+	pub fn draw(&self, msg: String) -> Result<(), Error> {
+		self.output(Output::Screen(msg))
+	}
+
+	fn output(&self, out: Output) -> Result<(), Error> {
+		match self.0.send(out) {
+			Ok(()) => Ok(()),
 			Err(e) => Err(Error::RuntimeError(format!("could not write to console: {}", e))),
 		}
 	}
@@ -0,0 +1,115 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+/// where a control channel should listen, parsed from a spec like
+///  * `tcp:127.0.0.1:13337` (or just `127.0.0.1:13337`)
+///  * `unix:/path/to/socket` (or just `unix` for a default path derived from pid)
+///  * `@name` or `unix:@name` for linux abstract unix sockets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+	Tcp(String),
+	Unix(PathBuf),
+	Abstract(String),
+}
+
+impl Endpoint {
+	pub fn default_unix() -> Self {
+		Endpoint::unix_for(std::process::id())
+	}
+
+	/// default unix socket path used by cordy inside process {pid}
+	pub fn unix_for(pid: u32) -> Self {
+		Endpoint::Unix(format!("/tmp/cordy-{}.sock", pid).into())
+	}
+}
+
+impl Default for Endpoint {
+	fn default() -> Self {
+		Endpoint::Tcp("127.0.0.1:13337".into())
+	}
+}
+
+impl FromStr for Endpoint {
+	type Err = String;
+
+	fn from_str(spec: &str) -> Result<Self, Self::Err> {
+		if let Some(name) = spec.strip_prefix('@') {
+			return unix_spec(&format!("@{}", name));
+		}
+		match spec.split_once(':') {
+			Some(("tcp", addr)) => tcp_spec(addr),
+			Some(("unix", path)) => unix_spec(path),
+			None if spec == "unix" => Ok(Endpoint::default_unix()),
+			_ => tcp_spec(spec),
+		}
+	}
+}
+
+fn tcp_spec(addr: &str) -> Result<Endpoint, String> {
+	match addr.rsplit_once(':') {
+		Some((_host, port)) if port.parse::<u16>().is_ok() => Ok(Endpoint::Tcp(addr.into())),
+		_ => Err(format!("invalid tcp address '{}', expected HOST:PORT", addr)),
+	}
+}
+
+fn unix_spec(path: &str) -> Result<Endpoint, String> {
+	if path.is_empty() {
+		Ok(Endpoint::default_unix())
+	} else if let Some(name) = path.strip_prefix('@') {
+		if name.is_empty() {
+			Err("abstract socket name can't be empty".into())
+		} else {
+			Ok(Endpoint::Abstract(name.into()))
+		}
+	} else {
+		Ok(Endpoint::Unix(path.into()))
+	}
+}
+
+impl Display for Endpoint {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Endpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+			Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+			Endpoint::Abstract(name) => write!(f, "unix:@{}", name),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tcp_specs() {
+		assert_eq!("127.0.0.1:1234".parse(), Ok(Endpoint::Tcp("127.0.0.1:1234".into())));
+		assert_eq!("tcp:0.0.0.0:80".parse(), Ok(Endpoint::Tcp("0.0.0.0:80".into())));
+		assert_eq!("[::1]:13337".parse(), Ok(Endpoint::Tcp("[::1]:13337".into())));
+	}
+
+	#[test]
+	fn unix_specs() {
+		assert_eq!("unix:/tmp/x.sock".parse(), Ok(Endpoint::Unix("/tmp/x.sock".into())));
+		assert_eq!("unix".parse(), Ok(Endpoint::default_unix()));
+		assert_eq!("unix:".parse(), Ok(Endpoint::default_unix()));
+	}
+
+	#[test]
+	fn abstract_specs() {
+		assert_eq!("@cordy".parse(), Ok(Endpoint::Abstract("cordy".into())));
+		assert_eq!("unix:@cordy".parse(), Ok(Endpoint::Abstract("cordy".into())));
+	}
+
+	#[test]
+	fn malformed_specs() {
+		for spec in ["", "@", "unix:@", "localhost", "tcp:localhost", "127.0.0.1:99999", "host:port"] {
+			assert!(spec.parse::<Endpoint>().is_err(), "{} should not parse", spec);
+		}
+	}
+
+	#[test]
+	fn specs_roundtrip_through_display() {
+		for spec in ["tcp:127.0.0.1:1", "unix:/tmp/a.sock", "unix:@name"] {
+			assert_eq!(spec.parse::<Endpoint>().unwrap().to_string(), spec);
+		}
+	}
+}
@@ -1,25 +1,246 @@
-use mlua::{Value, Table};
-
-pub fn pretty_lua(val: Value) -> String {
-	// TODO there must be some builtin to do this, right???
-	match val {
-		Value::Nil => "nil".into(),
-		Value::Boolean(b) => if b { "true".into() } else { "false".into() },
-		Value::LightUserData(x) => format!("LightUserData({:?})", x),
-		Value::Integer(n) => format!("{}", n),
-		Value::Number(n) => format!("{:.3}", n),
-		Value::String(s) => s.to_str().expect("string is not str").into(),
-		Value::Table(t) => try_serialize_table(&t),
-		Value::Function(f) => format!("Function({:?}", f),
-		Value::Thread(t) => format!("Thread({:?})", t),
-		Value::UserData(x) => format!("UserData({:?})", x),
-		Value::Error(e) => format!("Error({:?}) : {}", e, e.to_string()),
-	}
-}
-
-fn try_serialize_table(t: &Table) -> String {
-	match serde_json::to_string(t) {
-		Ok(txt) => txt,
-		Err(_e)  => format!("{:?}", t),
+use std::cell::OnceCell;
+
+use mlua::{Lua, Value, Table, Function};
+use tokio::time::Instant;
+
+/// tables whose one line rendering is longer than this get split on many lines
+const MAX_INLINE : usize = 72;
+
+/// how values get pretty printed, kept in lua app data and changed with printopts()
+#[derive(Debug, Clone)]
+pub struct PrettyOptions {
+	/// decimal digits of floats, shortest exact representation if unset
+	pub precision: Option<usize>,
+	/// nested tables deeper than this are not expanded
+	pub depth: usize,
+	/// spaces per indentation level
+	pub indent: usize,
+	/// show integers pointing inside mapped memory in hex
+	pub hex: bool,
+}
+
+impl Default for PrettyOptions {
+	fn default() -> Self {
+		PrettyOptions { precision: None, depth: 4, indent: 2, hex: true }
+	}
+}
+
+/// render a lua value for humans. strings are left as they are at top level, while
+/// inside tables they get quoted
+pub fn pretty_lua<'lua>(lua: &'lua Lua, val: Value<'lua>) -> String {
+	let opts = lua.app_data_ref::<PrettyOptions>().map(|o| o.clone()).unwrap_or_default();
+	Printer { lua, opts, maps: OnceCell::new(), stack: Vec::new() }.render(val, 0)
+}
+
+struct Printer<'lua> {
+	lua: &'lua Lua,
+	opts: PrettyOptions,
+	/// mapped memory ranges, only read if some integer could be an address
+	maps: OnceCell<Vec<(u64, u64)>>,
+	/// tables being rendered, to detect cycles
+	stack: Vec<Table<'lua>>,
+}
+
+impl<'lua> Printer<'lua> {
+	fn render(&mut self, val: Value<'lua>, level: usize) -> String {
+		match val {
+			Value::Nil => "nil".into(),
+			Value::Boolean(b) => if b { "true".into() } else { "false".into() },
+			Value::Integer(n) => self.integer(n),
+			Value::Number(n) => match self.opts.precision {
+				Some(p) => format!("{:.*}", p, n),
+				None => format!("{:?}", n), // keeps the point on integral floats, 1.0 isn't 1
+			},
+			Value::String(s) => {
+				let txt = String::from_utf8_lossy(s.as_bytes());
+				if level == 0 { txt.into() } else { format!("{:?}", txt) }
+			},
+			Value::Table(t) => {
+				if has_tostring(&t) {
+					return self.tostring(Value::Table(t));
+				}
+				self.table(t, level)
+			},
+			Value::Error(e) => format!("error: {}", e),
+			other => self.tostring(other),
+		}
+	}
+
+	fn integer(&self, n: i64) -> String {
+		if self.opts.hex && self.is_address(n) {
+			format!("0x{:X}", n)
+		} else {
+			format!("{}", n)
+		}
+	}
+
+	/// numbers pointing inside mapped memory are most likely addresses
+	fn is_address(&self, n: i64) -> bool {
+		if !(0x10000..0x8000_0000_0000).contains(&n) {
+			return false;
+		}
+		let maps = self.maps.get_or_init(|| {
+			procfs::process::Process::myself()
+				.and_then(|p| p.maps())
+				.map(|m| m.memory_maps.iter().map(|m| m.address).collect())
+				.unwrap_or_default()
+		});
+		maps.iter().any(|(start, end)| *start <= n as u64 && (n as u64) < *end)
+	}
+
+	/// let lua describe values we can't look into (functions, userdata...)
+	fn tostring(&self, val: Value<'lua>) -> String {
+		let res = self.lua.globals().get::<_, Function>("tostring")
+			.and_then(|f| f.call::<_, String>(val));
+		match res {
+			Ok(txt) => txt,
+			Err(e) => format!("<tostring failed: {}>", e),
+		}
+	}
+
+	fn table(&mut self, t: Table<'lua>, level: usize) -> String {
+		if self.stack.contains(&t) {
+			return "<cycle>".into();
+		}
+		if level >= self.opts.depth {
+			return "{...}".into();
+		}
+
+		let mut pairs : Vec<(Value, Value)> = match t.clone().pairs::<Value, Value>().collect() {
+			Ok(pairs) => pairs,
+			Err(e) => return format!("<could not iterate table: {}>", e),
+		};
+		if pairs.is_empty() {
+			return "{}".into();
+		}
+		pairs.sort_by_cached_key(|(k, _)| key_order(k));
+		let is_array = pairs.iter().enumerate().all(|(i, (k, _))| key_order(k) == (0, i as i64 + 1, vec![]));
+
+		self.stack.push(t);
+		let mut entries = Vec::with_capacity(pairs.len());
+		if is_array {
+			for (_, val) in pairs {
+				entries.push(self.render(val, level + 1));
+			}
+		} else {
+			for (key, val) in pairs {
+				let key = match &key {
+					Value::String(s) if s.to_str().is_ok_and(is_identifier) => String::from_utf8_lossy(s.as_bytes()).to_string(),
+					_ => format!("[{}]", self.render(key, level + 1)),
+				};
+				entries.push(format!("{} = {}", key, self.render(val, level + 1)));
+			}
+		}
+		self.stack.pop();
+
+		let inline = entries.iter().map(|e| e.len() + 2).sum::<usize>() + level * self.opts.indent <= MAX_INLINE
+			&& !entries.iter().any(|e| e.contains('\n'));
+		if inline {
+			return format!("{{ {} }}", entries.join(", "));
+		}
+		let pad = " ".repeat((level + 1) * self.opts.indent);
+		let mut out = String::from("{\n");
+		for entry in entries {
+			out.push_str(&pad);
+			out.push_str(&entry);
+			out.push_str(",\n");
+		}
+		out.push_str(&" ".repeat(level * self.opts.indent));
+		out.push('}');
+		out
+	}
+}
+
+fn has_tostring(t: &Table) -> bool {
+	t.get_metatable().is_some_and(|mt| !matches!(mt.raw_get::<_, Value>("__tostring"), Ok(Value::Nil) | Err(_)))
+}
+
+/// numbers first (by value), then strings (alphabetically), then anything else
+fn key_order(key: &Value) -> (u8, i64, Vec<u8>) {
+	match key {
+		Value::Integer(n) => (0, *n, vec![]),
+		Value::Number(n) if n.fract() == 0.0 => (0, *n as i64, vec![]),
+		Value::Number(n) => (0, *n as i64, n.to_string().into_bytes()),
+		Value::String(s) => (1, 0, s.as_bytes().to_vec()),
+		_ => (2, 0, vec![]),
+	}
+}
+
+pub const KEYWORDS : [&str; 22] = [
+	"and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+	"in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// whether {name} can be written bare, as in `t.name` or `{ name = 1 }`
+pub fn is_identifier(name: &str) -> bool {
+	let mut chars = name.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+		&& !KEYWORDS.contains(&name)
+}
+
+/// sleep until given deadline, or forever if there is none
+pub async fn sleep_until_deadline(deadline: Option<Instant>) {
+	match deadline {
+		Some(d) => tokio::time::sleep_until(d).await,
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pretty(lua: &Lua, code: &str) -> String {
+		pretty_lua(lua, lua.load(code).eval::<Value>().unwrap())
+	}
+
+	#[test]
+	fn scalars() {
+		let lua = Lua::new();
+		assert_eq!(pretty(&lua, "42"), "42");
+		assert_eq!(pretty(&lua, "1.5"), "1.5");
+		assert_eq!(pretty(&lua, "'top level'"), "top level");
+		assert_eq!(pretty(&lua, "{ 'nested' }"), "{ \"nested\" }");
+	}
+
+	#[test]
+	fn floats_keep_their_point() {
+		let lua = Lua::new();
+		assert_eq!(pretty_lua(&lua, Value::Number(1.0)), "1.0");
+		assert_eq!(pretty_lua(&lua, Value::Integer(1)), "1");
+	}
+
+	#[test]
+	fn arrays_and_maps() {
+		let lua = Lua::new();
+		assert_eq!(pretty(&lua, "{ 1, 2, 3 }"), "{ 1, 2, 3 }");
+		assert_eq!(pretty(&lua, "{ b = 2, a = 1 }"), "{ a = 1, b = 2 }");
+		assert_eq!(pretty(&lua, "{ [1] = 'x', [3] = 'y' }"), "{ [1] = \"x\", [3] = \"y\" }");
+		assert_eq!(pretty(&lua, "{ 1, x = true, ['not id'] = 0 }"), "{ [1] = 1, [\"not id\"] = 0, x = true }");
+		assert_eq!(pretty(&lua, "{}"), "{}");
+		assert_eq!(pretty(&lua, "{ ['end'] = 1, ending = 2 }"), "{ [\"end\"] = 1, ending = 2 }");
+	}
+
+	#[test]
+	fn cycles_are_cut() {
+		let lua = Lua::new();
+		assert_eq!(pretty(&lua, "local t = { 1 } t[2] = t return t"), "{ 1, <cycle> }");
+		assert_eq!(pretty(&lua, "local s = {} return { s, s }"), "{ {}, {} }");
+	}
+
+	#[test]
+	fn depth_is_limited() {
+		let lua = Lua::new();
+		assert_eq!(pretty(&lua, "{ { { { { 1 } } } } }"), "{ { { { {...} } } } }");
+		lua.set_app_data(PrettyOptions { depth: 1, ..Default::default() });
+		assert_eq!(pretty(&lua, "{ { 1 }, 2 }"), "{ {...}, 2 }");
+	}
+
+	#[test]
+	fn long_tables_get_split() {
+		let lua = Lua::new();
+		let out = pretty(&lua, "{ string.rep('a', 40), string.rep('b', 40) }");
+		assert_eq!(out, format!("{{\n  \"{}\",\n  \"{}\",\n}}", "a".repeat(40), "b".repeat(40)));
 	}
 }
@@ -0,0 +1,136 @@
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, sync::Mutex};
+
+use tracing::warn;
+
+/// how many lines are remembered, older ones get dropped
+pub const MAX_HISTORY : usize = 1000;
+
+/// lines entered by every session of this process, so new connections can recall
+/// them. loaded from history file (if any) when first session opens
+static RECENT: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// lines entered in the repl, browsable with up/down and searchable.
+/// each session gets its own copy, new lines are also stored process wide
+/// and appended to history file
+pub struct History {
+	entries: Vec<String>,
+	path: Option<PathBuf>,
+	/// entry currently shown while browsing, entries.len() means the line being edited
+	index: usize,
+	/// what was being typed before browsing started
+	draft: String,
+}
+
+impl History {
+	pub fn open(path: Option<PathBuf>) -> Self {
+		let mut recent = RECENT.lock().expect("history lock is poisoned");
+		let entries = recent.get_or_insert_with(|| match &path {
+			Some(path) => load(path),
+			None => Vec::new(),
+		}).clone();
+		History { index: entries.len(), entries, path, draft: String::new() }
+	}
+
+	/// history starting empty whatever other sessions entered, so tests don't see each other
+	#[cfg(test)]
+	pub fn isolated() -> Self {
+		History { entries: Vec::new(), path: None, index: 0, draft: String::new() }
+	}
+
+	pub fn entries(&self) -> &[String] {
+		&self.entries
+	}
+
+	/// remember a submitted line, skipping blanks and immediate repetitions
+	pub fn push(&mut self, line: &str) {
+		self.reset();
+		if line.trim().is_empty() || self.entries.last().map(|l| l.as_str()) == Some(line) {
+			return;
+		}
+		self.entries.push(line.to_string());
+		trim(&mut self.entries);
+		self.index = self.entries.len();
+
+		if let Some(recent) = RECENT.lock().expect("history lock is poisoned").as_mut() {
+			recent.push(line.to_string());
+			trim(recent);
+		}
+
+		if let Some(path) = &self.path {
+			let res = OpenOptions::new().create(true).append(true).mode(0o600).open(path)
+				.and_then(|mut f| writeln!(f, "{}", line));
+			if let Err(e) = res {
+				warn!("could not append to history file {}: {}", path.display(), e);
+			}
+		}
+	}
+
+	/// stop browsing, next up will start again from newest entry
+	pub fn reset(&mut self) {
+		self.index = self.entries.len();
+		self.draft.clear();
+	}
+
+	/// older entry, {current} is kept as draft when browsing starts
+	pub fn prev(&mut self, current: &str) -> Option<&str> {
+		if self.index == 0 {
+			return None;
+		}
+		if self.index == self.entries.len() {
+			self.draft = current.to_string();
+		}
+		self.index -= 1;
+		Some(&self.entries[self.index])
+	}
+
+	/// newer entry, going past newest one gives back the draft
+	pub fn next(&mut self) -> Option<&str> {
+		if self.index >= self.entries.len() {
+			return None;
+		}
+		self.index += 1;
+		match self.entries.get(self.index) {
+			Some(entry) => Some(entry),
+			None => Some(&self.draft),
+		}
+	}
+
+	/// newest entry containing {query}, strictly older than entry {before}
+	pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+		self.entries[..before.min(self.entries.len())]
+			.iter()
+			.rposition(|l| l.contains(query))
+	}
+
+	pub fn get(&self, index: usize) -> Option<&str> {
+		self.entries.get(index).map(|l| l.as_str())
+	}
+}
+
+fn trim(entries: &mut Vec<String>) {
+	if entries.len() > MAX_HISTORY {
+		entries.drain(..entries.len() - MAX_HISTORY);
+	}
+}
+
+/// read history file, compacting it if it grew too much
+fn load(path: &Path) -> Vec<String> {
+	let txt = match std::fs::read_to_string(path) {
+		Ok(txt) => txt,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+		Err(e) => {
+			warn!("could not read history file {}: {}", path.display(), e);
+			return Vec::new();
+		},
+	};
+	let mut entries : Vec<String> = txt.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect();
+	if entries.len() > 2 * MAX_HISTORY {
+		trim(&mut entries);
+		let compact = entries.iter().fold(String::new(), |acc, l| acc + l + "\n");
+		if let Err(e) = std::fs::write(path, compact) {
+			warn!("could not compact history file {}: {}", path.display(), e);
+		}
+	}
+	trim(&mut entries);
+	entries
+}
@@ -1,47 +1,102 @@
+mod auth;
+mod autorun;
+mod budget;
 mod channel;
+mod complete;
+mod config;
+mod endpoint;
 mod helpers;
+mod history;
 mod console;
 mod repl;
+mod rpc;
+mod state;
+mod tls;
 mod tools;
+mod transcript;
 
-use channel::ControlChannel;
-use tracing::error;
+use std::sync::Mutex;
+
+use channel::{ControlChannel, Protocol};
+use config::Config;
+use state::{shared, MAIN_STATE};
+use tracing::{error, info};
 
 #[ctor::ctor]
 fn contructor() {
+	if cfg!(test) {
+		return; // unit tests run their own pieces, don't open control channels
+	}
 	std::thread::spawn(move || -> Result<(), std::io::Error> {
-		tracing_subscriber::fmt()
-			.with_max_level(tracing::Level::DEBUG)
-			.with_writer(std::io::stderr)
-			.init();
+		let (cfg, cfg_err) = match Config::load() {
+			Ok(cfg) => (cfg, None),
+			Err(e) => (Config::default(), Some(e)),
+		};
+		let subscriber = tracing_subscriber::fmt()
+			.with_max_level(cfg.log_level);
+		match cfg.log_file.as_ref().map(|path| std::fs::OpenOptions::new().create(true).append(true).open(path)) {
+			Some(Ok(file)) => subscriber.with_ansi(false).with_writer(Mutex::new(file)).init(),
+			Some(Err(e)) => {
+				subscriber.with_writer(std::io::stderr).init();
+				error!("could not open log file, logging to stderr: {}", e);
+			},
+			None => subscriber.with_writer(std::io::stderr).init(),
+		}
+		if let Some(e) = cfg_err {
+			// defaults would drop token, allowlists and tls: better not to listen at all
+			error!("invalid configuration, cordy will stay idle: {}", e);
+			return Ok(());
+		}
 		tokio::runtime::Builder::new_current_thread()
 			.enable_all()
 			.build()?
-			.block_on(main());
+			.block_on(main(cfg));
 		Ok(())
 	});
 }
 
 #[ctor::dtor]
-fn destructor() {}
+fn destructor() {
+	channel::remove_socket_files();
+}
 
 
 
 
-async fn main() {
-	let mut handle = ControlChannel::run("127.0.0.1:13337".into());
+async fn main(cfg: Config) {
+	let state = match shared(MAIN_STATE) {
+		Ok(state) => Some(state),
+		Err(e) => {
+			error!("could not create main lua state: {}", e);
+			None
+		},
+	};
 
-	loop {
-		match handle.source.recv().await {
-			Ok(txt) => {
-				if let Err(e) = handle.sink.send(txt).await {
-					error!("could not echo back txt: {}", e);
-				}
-			},
-			Err(e) => {
-				error!("controller data channel is closed: {}", e);
-				break;
-			}
+	if let (Some(state), Some(path)) = (&state, &cfg.autorun) {
+		if let Err(e) = state.script(path.clone()).await {
+			error!("could not run autorun scripts: {}", e);
+		}
+	}
+
+	let mut channels = vec![];
+	if let Some(addr) = cfg.listen.clone() {
+		channels.push(ControlChannel::run(addr, Protocol::Repl, &cfg));
+	}
+	if let Some(addr) = cfg.rpc_listen.clone() {
+		channels.push(ControlChannel::run(addr, Protocol::Rpc, &cfg));
+	}
+	if let Some(addr) = cfg.connect.clone() {
+		channels.push(ControlChannel::connect(addr, &cfg));
+	}
+
+	if channels.is_empty() {
+		info!("no control channel configured, keeping lua states alive");
+		std::future::pending::<()>().await;
+	}
+
+	for channel in channels {
+		if let Err(e) = channel.await {
+			error!("control channel stopped unexpectedly: {}", e);
 		}
 	}
 }
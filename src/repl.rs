@@ -1,36 +1,122 @@
-use mlua::{Error, Lua, MultiValue};
+use mlua::Error;
 
-use crate::helpers::pretty_lua;
+use std::time::Instant;
+
+use crate::{budget::Limits, history::History, state::{LuaState, LuaStateHandle, Outcome, shared, shared_names}, transcript::Transcript};
 
 use super::console::Console;
 
-const BS : char = '\u{8}';  // backspace, \b, <BS>, ^B
-const FF : char = '\u{C}';  // line feed, <C-L>, ^L
+const BS : char = '\u{8}';  // backspace, \b, <BS>, ^H
+const DEL: char = '\u{7F}'; // what most terminals send for backspace in raw mode
+const FF : char = '\u{C}';  // form feed, <C-L>, ^L
 const CMD: char = '\u{1B}'; // ANSI escape char
-const CR : char = '\u{A}';  // newline, \n, 10
+const LF : char = '\u{A}';  // newline, \n, 10
+const CR : char = '\u{D}';  // carriage return, \r, 13, what enter sends in raw mode
+const SOH: char = '\u{1}';  // <C-A>, go to line start
+const ENQ: char = '\u{5}';  // <C-E>, go to line end
+const VT : char = '\u{B}';  // <C-K>, kill until line end
+const NAK: char = '\u{15}'; // <C-U>, kill until line start
+const DC2: char = '\u{12}'; // <C-R>, reverse search history
+const BEL: char = '\u{7}';  // <C-G>, abort history search
+const HT : char = '\u{9}';  // tab, complete identifier under cursor
+pub const ETX: char = '\u{3}'; // <C-C>, interrupt evaluation or discard line
+
+const PROMPT : &str = "@> ";
+const CONTINUE : &str = "@    ";
+
+/// when completion is this ambiguous, don't list every candidate
+const MAX_CANDIDATES : usize = 100;
 
 pub const VERSIONTEXT : &str = "LuaJit 5.2 via rlua";
 
-enum CmdStep {
+const COMMANDS : &str = "?> Lines starting with a dot are repl commands:
+ >  .load FILE            evaluate a lua file from target filesystem
+ >  .save FILE            write code evaluated in this session to FILE
+ >  .reset                replace this session lua state with a fresh one
+ >  .history [N]          show last {N} history entries
+ >  .output json|text     show results serialized as json, or pretty printed
+ >  .time on|off          show how long each evaluation took
+ >  .quit                 close this session
+ >  .help                 print this message
+";
+
+/// where we are inside an ANSI escape sequence
+enum Escape {
 	Nope,
-	One,
-	Two,
+	/// got ESC
+	Start,
+	/// got ESC [, collecting parameter and intermediate bytes
+	Csi(String),
+	/// got ESC O, next byte is final
+	Ss3,
+}
+
+/// editing keys we understand, decoded from escape sequences
+enum Key {
+	Up,
+	Down,
+	Left,
+	Right,
+	Home,
+	End,
+	Delete,
+}
+
+/// incremental reverse search through history, started with ^R
+struct Search {
+	query: String,
+	/// history entry currently matching query
+	found: Option<usize>,
+	/// line being edited when search started, restored if search gets aborted
+	original: String,
 }
 
 pub struct LuaRepl {
 	buffer: String,
+	/// byte offset of cursor inside buffer, always on a char boundary
+	cursor: usize,
 	console: Console,
-	cmd: CmdStep,
-	cmdbuf: u16,
+	state: LuaStateHandle,
+	escape: Escape,
+	prompt: &'static str,
+	/// client terminal is in raw mode: we must echo input and draw the line ourselves
+	raw: bool,
+	/// last char was a CR, so a following LF (or NUL) is part of the same newline
+	after_cr: bool,
+	history: History,
+	search: Option<Search>,
+	/// bytes of a multibyte utf8 char still being received
+	pending: Vec<u8>,
+	limits: Limits,
+	/// code evaluated in this session, for .save
+	entered: Vec<String>,
+	json: bool,
+	timing: bool,
+	/// session asked to quit
+	closed: bool,
+	transcript: Option<Transcript>,
 }
 
 impl LuaRepl {
-	pub fn new(console: Console) -> Self {
+	pub fn new(console: Console, state: LuaStateHandle, history: History, limits: Limits, transcript: Option<Transcript>) -> Self {
 		Self {
 			console,
+			state,
 			buffer: String::new(),
-			cmd: CmdStep::Nope,
-			cmdbuf: 0,
+			cursor: 0,
+			escape: Escape::Nope,
+			prompt: PROMPT,
+			raw: false,
+			after_cr: false,
+			history,
+			search: None,
+			pending: Vec::new(),
+			limits,
+			entered: Vec::new(),
+			json: false,
+			timing: false,
+			closed: false,
+			transcript,
 		}
 	}
 
@@ -38,75 +124,637 @@ impl LuaRepl {
 		self.buffer.clone()
 	}
 
+	/// raw terminals need "\r\n" rather than just "\n"
+	pub fn raw(&self) -> bool {
+		self.raw
+	}
+
+	/// client asked to close session with .quit
+	pub fn closed(&self) -> bool {
+		self.closed
+	}
+
 	pub fn write(&self, txt: String) -> Result<(), Error> {
 		self.console.send(txt)
 	}
 
+	pub fn prompt(&self) -> Result<(), Error> {
+		self.console.draw(self.prompt.into())
+	}
+
+	/// note output reaching the client in the transcript, if recording
+	pub fn record(&mut self, txt: &str) {
+		if let Some(transcript) = self.transcript.as_mut() {
+			transcript.output(txt);
+		}
+	}
+
+	/// decode utf8 input as it arrives, one byte at a time. invalid sequences
+	/// become U+FFFD rather than breaking the session
+	pub async fn feed(&mut self, byte: u8) -> Result<(), Error> {
+		self.pending.push(byte);
+		match std::str::from_utf8(&self.pending) {
+			Ok(txt) => {
+				let ch = txt.chars().next();
+				self.pending.clear();
+				if let Some(ch) = ch {
+					self.evaluate(ch).await?;
+				}
+			},
+			Err(e) if e.error_len().is_none() => {}, // wait for the rest
+			Err(_) => {
+				let broken = self.pending.len() > 1;
+				self.pending.clear();
+				self.evaluate(char::REPLACEMENT_CHARACTER).await?;
+				// a truncated sequence may be followed by a whole new char
+				if broken && byte < 0x80 {
+					self.evaluate(byte as char).await?;
+				} else if broken && byte >= 0xC0 {
+					self.pending.push(byte);
+				}
+			},
+		}
+		Ok(())
+	}
+
 	/// note that errors produced by repl are related to our environment,
 	/// all Lua errors will be caught and printed on the console
-	pub fn evaluate(&mut self, lua: &Lua, ch: char) -> Result<(), Error> {
-		match self.cmd {
-			CmdStep::Nope => self.eval(lua, ch)?,
-			CmdStep::One => {
-				self.cmdbuf |= ch as u16;
-				self.cmd = CmdStep::Two;
-			}
-			CmdStep::Two => {
-				self.cmdbuf |= (ch as u16) << 8;
+	pub async fn evaluate(&mut self, ch: char) -> Result<(), Error> {
+		let after_cr = std::mem::replace(&mut self.after_cr, false);
+		match std::mem::replace(&mut self.escape, Escape::Nope) {
+			Escape::Nope => {
+				if after_cr && (ch == LF || ch == '\0') {
+					return Ok(()); // telnet style CR LF or CR NUL
+				}
+				self.eval(ch).await?
+			},
+			Escape::Start => match ch {
+				'[' => self.escape = Escape::Csi(String::new()),
+				'O' => self.escape = Escape::Ss3,
+				CMD => self.escape = Escape::Start,
+				_ => {}, // alt+key or lone ESC, ignore
+			},
+			Escape::Ss3 => if let Some(key) = decode("", ch) {
+				self.key(key)?;
+			},
+			Escape::Csi(mut params) => match ch {
+				'\u{20}'..='\u{3F}' => {
+					if params.len() < 16 { params.push(ch); }
+					self.escape = Escape::Csi(params);
+				},
+				'\u{40}'..='\u{7E}' => if let Some(key) = decode(&params, ch) {
+					self.key(key)?;
+				},
+				_ => {}, // malformed sequence, drop it
+			},
+		}
 
-				// TODO parse escape codes? they aren't even all 2 bytes...
-				// self.console.send(format!("{}{}{}", BS, BS, BS))?;
+		Ok(())
+	}
 
-				self.cmdbuf = 0;
-				self.cmd = CmdStep::Nope;
+	async fn eval(&mut self, ch: char) -> Result<(), Error> {
+		if self.search.is_some() && self.search_input(ch)? {
+			return Ok(());
+		}
+		match ch {
+			BS | DEL => if let Some(prev) = self.prev_boundary() {
+				self.buffer.replace_range(prev..self.cursor, "");
+				self.cursor = prev;
+				self.redraw()?;
+			},
+			FF => self.console.draw(format!("\n{}{}", self.prompt, self.buffer))?,
+			CMD => self.escape = Escape::Start,
+			SOH => self.key(Key::Home)?,
+			ENQ => self.key(Key::End)?,
+			VT => {
+				self.buffer.truncate(self.cursor);
+				self.redraw()?;
+			},
+			NAK => {
+				self.buffer.replace_range(self.line_start()..self.cursor, "");
+				self.cursor = self.line_start();
+				self.redraw()?;
+			},
+			DC2 => {
+				self.search = Some(Search { query: String::new(), found: None, original: self.line().to_string() });
+				self.redraw()?;
+			},
+			LF | CR => {
+				if ch == CR {
+					self.raw = true;
+					self.after_cr = true;
+				}
+				if self.raw {
+					self.console.draw("\n".into())?;
+				}
+				self.submit().await?;
+			},
+			HT if self.raw => self.complete().await?,
+			ETX => {
+				// nothing is running, or the session would have interrupted it
+				self.buffer.clear();
+				self.cursor = 0;
+				self.prompt = PROMPT;
+				self.history.reset();
+				self.console.draw(format!("^C\n{}", PROMPT))?;
+			},
+			'\0' => return Err(Error::RuntimeError("null byte in stream".into())),
+			_ => {
+				self.buffer.insert(self.cursor, ch);
+				self.cursor += ch.len_utf8();
+				if self.cursor == self.buffer.len() {
+					if self.raw { self.console.draw(ch.to_string())?; }
+				} else {
+					self.redraw()?;
+				}
 			},
 		}
+		Ok(())
+	}
 
+	async fn submit(&mut self) -> Result<(), Error> {
+		let line = self.line().to_string();
+		self.history.push(&line);
+		if let Some(transcript) = self.transcript.as_mut() {
+			transcript.input(&line);
+		}
+		if self.line_start() == 0 && line.trim_start().starts_with('.') {
+			self.command(line.trim()).await?;
+		} else {
+			let code = self.buffer.clone();
+			if !self.run(code.clone()).await? {
+				self.prompt = CONTINUE;
+				self.console.draw(CONTINUE.into())?;
+				self.buffer.push(LF);
+				self.cursor = self.buffer.len();
+				return Ok(());
+			}
+			self.entered.push(code);
+		}
+		self.buffer = String::new();
+		self.cursor = 0;
+		self.prompt = PROMPT;
+		if !self.closed {
+			self.console.draw(PROMPT.into())?;
+		}
 		Ok(())
 	}
 
-	fn eval(&mut self, lua: &Lua, ch: char) -> Result<(), Error> {
-		match ch {
-			BS => {
-				if self.buffer.len() > 0 {
-					self.buffer.remove(self.buffer.len() - 1);
+	/// evaluate and display results, returns false if code is incomplete
+	async fn run(&mut self, code: String) -> Result<bool, Error> {
+		let start = Instant::now();
+		let evaluation = if self.json {
+			self.state.eval_json(code, self.console.clone(), self.limits.clone()).await?
+		} else {
+			self.state.eval(code, self.console.clone(), self.limits.clone()).await?
+		};
+		match evaluation.outcome {
+			Outcome::Values(values) => {
+				let mut once = false;
+				for (type_name, val) in values {
+					once = true;
+					self.console.send(
+						format!("=({}) {}", type_name, val)
+					)?;
+				}
+				if once { self.console.send("\n".into())?; }
+			},
+			Outcome::Json(values) => {
+				for val in values.iter() {
+					self.console.send(format!("{}\n", val))?;
 				}
 			},
-			FF => self.console.send(format!("\n@> {}", self.buffer))?,
-			CMD => self.cmd = CmdStep::One,
-			CR => {
-				match lua.load(&self.buffer).eval::<MultiValue>() {
-					Ok(values) => {
-						let mut once = false;
-						for val in values {
-							once = true;
-							self.console.send(
-								format!("=({}) {}", val.type_name(), pretty_lua(val))
-							)?;
-						}
-						self.console.send(format!("{}@> ", if once { "\n" } else { "" }))?;
-						self.buffer = String::new();
-					},
-					Err(e) => {
-						match e {
-							mlua::Error::SyntaxError {
-								message: _,
-								incomplete_input: true
-							} => {
-								self.console.send("@    ".into())?;
-								self.buffer.push(ch);
-							},
-							_ => {
-								self.console.send(format!("! {}\n@> ", e))?;
-								self.buffer = String::new();
-							},
-						}
+			Outcome::Incomplete => return Ok(false),
+			Outcome::Failed(e) => self.console.send(format!("! {}\n", e))?,
+		}
+		if self.timing {
+			self.console.send(format!("~ took {:.3?}\n", start.elapsed()))?;
+		}
+		if let (Some(on), Some(transcript)) = (evaluation.record, self.transcript.as_mut()) {
+			transcript.set_enabled(on);
+		}
+		if let Some(name) = evaluation.attach {
+			self.attach(&name)?;
+		}
+		Ok(true)
+	}
+
+	/// meta commands, handled here rather than by lua
+	async fn command(&mut self, line: &str) -> Result<(), Error> {
+		let (cmd, arg) = match line.split_once(char::is_whitespace) {
+			Some((cmd, arg)) => (cmd, arg.trim()),
+			None => (line, ""),
+		};
+		match (cmd, arg) {
+			(".help", _) => self.console.send(COMMANDS.into())?,
+			(".quit", _) | (".exit", _) => {
+				self.closed = true;
+				self.console.send("~ bye\n".into())?;
+			},
+			(".load", "") | (".save", "") => self.console.send(format!("! usage: {} FILE\n", cmd))?,
+			(".load", path) => match std::fs::read_to_string(path) {
+				Ok(code) => if !self.run(code).await? {
+					self.console.send(format!("! {} is incomplete\n", path))?;
+				},
+				Err(e) => self.console.send(format!("! could not read {}: {}\n", path, e))?,
+			},
+			(".save", path) => {
+				let mut txt = self.entered.join("\n");
+				txt.push('\n');
+				match std::fs::write(path, txt) {
+					Ok(()) => self.console.send(format!("~ saved {} entries to {}\n", self.entered.len(), path))?,
+					Err(e) => self.console.send(format!("! could not write {}: {}\n", path, e))?,
+				}
+			},
+			(".reset", _) => self.reset()?,
+			(".history", n) => {
+				let n = if n.is_empty() { 20 } else {
+					match n.parse::<usize>() {
+						Ok(n) => n,
+						Err(e) => return self.console.send(format!("! invalid count '{}': {}\n", n, e)),
 					}
+				};
+				let entries = self.history.entries();
+				let skip = entries.len().saturating_sub(n);
+				let mut out = String::new();
+				for (i, entry) in entries.iter().enumerate().skip(skip) {
+					out.push_str(&format!("{:5}  {}\n", i + 1, entry));
 				}
+				self.console.send(out)?;
 			},
-			'\0' => return Err(Error::RuntimeError("null byte in stream".into())),
-			_ => self.buffer.push(ch),
+			(".output", "json") => self.json = true,
+			(".output", "text") => self.json = false,
+			(".output", "") => self.console.send(format!("~ output is {}\n", if self.json { "json" } else { "text" }))?,
+			(".output", _) => self.console.send("! usage: .output json|text\n".into())?,
+			(".time", "on") => self.timing = true,
+			(".time", "off") => self.timing = false,
+			(".time", "") => self.console.send(format!("~ timing is {}\n", if self.timing { "on" } else { "off" }))?,
+			(".time", _) => self.console.send("! usage: .time on|off\n".into())?,
+			_ => self.console.send(format!("! unknown command {}, try .help\n", cmd))?,
 		}
 		Ok(())
 	}
+
+	/// swap our state for a fresh one, refusing to touch shared ones
+	fn reset(&mut self) -> Result<(), Error> {
+		let name = self.state.name().to_string();
+		if shared_names().contains(&name) {
+			return self.console.send(format!("! state '{}' is shared, attach to another one instead\n", name));
+		}
+		match LuaState::run(name.clone(), Some(self.console.clone())) {
+			Ok(state) => {
+				self.state = state;
+				self.entered.clear();
+				self.console.send(format!("~ state '{}' reset\n", name))
+			},
+			Err(e) => self.console.send(format!("! could not create new state: {}\n", e)),
+		}
+	}
+
+	fn key(&mut self, key: Key) -> Result<(), Error> {
+		match key {
+			Key::Left => if let Some(prev) = self.prev_boundary() {
+				self.cursor = prev;
+			},
+			Key::Right => if let Some(next) = self.next_boundary() {
+				self.cursor = next;
+			},
+			Key::Home => self.cursor = self.line_start(),
+			Key::End => self.cursor = self.buffer.len(),
+			Key::Delete => if let Some(next) = self.next_boundary() {
+				self.buffer.replace_range(self.cursor..next, "");
+			},
+			Key::Up => {
+				let current = self.line().to_string();
+				match self.history.prev(&current).map(str::to_string) {
+					Some(entry) => self.replace_line(entry),
+					None => return Ok(()),
+				}
+			},
+			Key::Down => match self.history.next().map(str::to_string) {
+				Some(entry) => self.replace_line(entry),
+				None => return Ok(()),
+			},
+		}
+		self.redraw()
+	}
+
+	/// complete identifier under cursor asking the state what's there, list candidates
+	/// if they're ambiguous. cooked terminals only send whole lines, so they just get a tab
+	async fn complete(&mut self) -> Result<(), Error> {
+		let before = &self.buffer[self.line_start()..self.cursor];
+		let start = before.char_indices().rev()
+			.find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '.' || *c == ':'))
+			.map_or(0, |(i, c)| i + c.len_utf8());
+		let word = before[start..].to_string();
+		if word.starts_with(|c: char| c.is_ascii_digit()) {
+			return self.console.draw(BEL.into());
+		}
+		let typed = word.len() - word.rfind(['.', ':']).map_or(0, |i| i + 1);
+
+		let candidates = self.state.complete(word).await?;
+		let common = common_prefix(&candidates);
+		if common.len() > typed {
+			self.buffer.insert_str(self.cursor, &common[typed..]);
+			self.cursor += common.len() - typed;
+			return self.redraw();
+		}
+		if candidates.len() < 2 {
+			return self.console.draw(BEL.into());
+		}
+
+		let mut list = candidates.iter().take(MAX_CANDIDATES).cloned().collect::<Vec<String>>().join("  ");
+		if candidates.len() > MAX_CANDIDATES {
+			list.push_str(&format!("  ... ({} more)", candidates.len() - MAX_CANDIDATES));
+		}
+		self.console.draw(format!("\n{}\n", list))?;
+		self.redraw()
+	}
+
+	/// handle a char while searching history, returns false if it ended the
+	/// search and should also be handled as usual (like enter or arrows)
+	fn search_input(&mut self, ch: char) -> Result<bool, Error> {
+		let search = match self.search.as_mut() {
+			Some(search) => search,
+			None => return Ok(false),
+		};
+		match ch {
+			DC2 => {
+				let before = search.found.unwrap_or(usize::MAX);
+				if let Some(found) = self.history.search(&search.query, before) {
+					search.found = Some(found);
+				}
+			},
+			BS | DEL => {
+				search.query.pop();
+				search.found = self.history.search(&search.query, usize::MAX);
+			},
+			BEL => {
+				let original = std::mem::take(&mut search.original);
+				self.search = None;
+				self.replace_line(original);
+			},
+			_ if !ch.is_control() => {
+				search.query.push(ch);
+				let before = search.found.map_or(usize::MAX, |i| i + 1);
+				search.found = self.history.search(&search.query, before);
+			},
+			_ => {
+				let line = match search.found.and_then(|i| self.history.get(i)) {
+					Some(entry) => entry.to_string(),
+					None => std::mem::take(&mut search.original),
+				};
+				self.search = None;
+				self.replace_line(line);
+				self.redraw()?;
+				return Ok(false);
+			},
+		}
+		self.redraw()?;
+		Ok(true)
+	}
+
+	/// line being edited, previous ones were already submitted as incomplete input
+	fn line(&self) -> &str {
+		&self.buffer[self.line_start()..]
+	}
+
+	fn replace_line(&mut self, line: String) {
+		self.buffer.truncate(self.line_start());
+		self.buffer.push_str(&line);
+		self.cursor = self.buffer.len();
+	}
+
+	/// lines already submitted as incomplete input can't be edited anymore
+	fn line_start(&self) -> usize {
+		self.buffer.rfind(LF).map_or(0, |i| i + 1)
+	}
+
+	fn prev_boundary(&self) -> Option<usize> {
+		if self.cursor <= self.line_start() { return None; }
+		self.buffer[..self.cursor].char_indices().last().map(|(i, _)| i)
+	}
+
+	fn next_boundary(&self) -> Option<usize> {
+		self.buffer[self.cursor..].chars().next().map(|c| self.cursor + c.len_utf8())
+	}
+
+	/// repaint current line and put terminal cursor back where ours is.
+	/// only needed when client terminal is raw, otherwise it edits on its own
+	fn redraw(&self) -> Result<(), Error> {
+		if !self.raw {
+			return Ok(());
+		}
+		if let Some(search) = &self.search {
+			let found = search.found.and_then(|i| self.history.get(i)).unwrap_or_default();
+			return self.console.draw(format!("\r(reverse-i-search)`{}': {}\u{1B}[K", search.query, found));
+		}
+		let behind = self.buffer[self.cursor..].chars().count();
+		let mut out = format!("\r{}{}\u{1B}[K", self.prompt, self.line());
+		if behind > 0 {
+			out.push_str(&format!("\u{1B}[{}D", behind));
+		}
+		self.console.draw(out)
+	}
+
+	fn attach(&mut self, name: &str) -> Result<(), Error> {
+		match shared(name) {
+			Ok(state) => {
+				self.state = state;
+				self.console.send(format!("~ attached to state '{}'\n", name))
+			},
+			Err(e) => self.console.send(format!("! could not attach to state '{}': {}\n", name, e)),
+		}
+	}
+}
+
+fn common_prefix(names: &[String]) -> &str {
+	let first = match names.first() {
+		Some(first) => first.as_str(),
+		None => return "",
+	};
+	let len = names.iter()
+		.map(|n| first.bytes().zip(n.bytes()).take_while(|(a, b)| a == b).count())
+		.min()
+		.unwrap_or(0);
+	&first[..len]
+}
+
+/// map final byte (and parameters) of CSI and SS3 sequences to keys
+fn decode(params: &str, fin: char) -> Option<Key> {
+	match (params, fin) {
+		(_, 'A') => Some(Key::Up),
+		(_, 'B') => Some(Key::Down),
+		(_, 'C') => Some(Key::Right),
+		(_, 'D') => Some(Key::Left),
+		(_, 'H') => Some(Key::Home),
+		(_, 'F') => Some(Key::End),
+		("1", '~') | ("7", '~') => Some(Key::Home),
+		("4", '~') | ("8", '~') => Some(Key::End),
+		("3", '~') => Some(Key::Delete),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc;
+
+	use crate::console::Output;
+
+	use super::*;
+
+	fn repl() -> (LuaRepl, mpsc::UnboundedReceiver<Output>) {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let console = Console::from(tx);
+		let state = LuaState::run("repl-test".into(), Some(console.clone())).expect("could not start lua state");
+		(LuaRepl::new(console, state, History::isolated(), Limits::default(), None), rx)
+	}
+
+	async fn feed(repl: &mut LuaRepl, bytes: &[u8]) {
+		for b in bytes {
+			repl.feed(*b).await.expect("repl failed");
+		}
+	}
+
+	#[tokio::test]
+	async fn up_and_down_recall_history() {
+		let (mut repl, _rx) = repl();
+		feed(&mut repl, b"x = 1\r").await;
+		feed(&mut repl, b"y = 2\r").await;
+		feed(&mut repl, b"draft").await;
+		feed(&mut repl, b"\x1b[A").await;
+		assert_eq!(repl.buffer(), "y = 2");
+		feed(&mut repl, b"\x1b[A").await;
+		assert_eq!(repl.buffer(), "x = 1");
+		feed(&mut repl, b"\x1bOB").await;
+		assert_eq!(repl.buffer(), "y = 2");
+		feed(&mut repl, b"\x1b[B").await;
+		assert_eq!(repl.buffer(), "draft");
+		feed(&mut repl, b"\x1b[B").await;
+		assert_eq!(repl.buffer(), "draft");
+	}
+
+	#[tokio::test]
+	async fn escape_sequences_split_across_reads() {
+		let (mut repl, _rx) = repl();
+		feed(&mut repl, b"ab").await;
+		for b in b"\x1b[D" {
+			feed(&mut repl, &[*b]).await;
+		}
+		feed(&mut repl, b"X").await;
+		assert_eq!(repl.buffer(), "aXb");
+		feed(&mut repl, b"\x1b[1;5D\x1bOHY").await; // ctrl-left, then home
+		assert_eq!(repl.buffer(), "YaXb");
+		feed(&mut repl, b"\x1b[3~").await;
+		assert_eq!(repl.buffer(), "YXb");
+		feed(&mut repl, b"\x1b[4~Z").await;
+		assert_eq!(repl.buffer(), "YXbZ");
+	}
+
+	#[tokio::test]
+	async fn unknown_sequences_are_swallowed() {
+		let (mut repl, _rx) = repl();
+		feed(&mut repl, b"a\x1b[2Jb\x1b[99~c\x1b[?25hd\x1bOxe").await;
+		assert_eq!(repl.buffer(), "abcde");
+		assert_eq!(repl.cursor, 5);
+		feed(&mut repl, &[0x1b, b'[', b'1', 0x01]).await; // malformed, control byte inside
+		feed(&mut repl, b"f").await;
+		assert_eq!(repl.buffer(), "abcdef");
+		feed(&mut repl, format!("\x1b[{}A", "9".repeat(100)).as_bytes()).await;
+		assert_eq!(repl.buffer(), "abcdef");
+	}
+
+	#[tokio::test]
+	async fn multibyte_chars_split_across_reads() {
+		let (mut repl, _rx) = repl();
+		feed(&mut repl, "é€😀".as_bytes()).await;
+		assert_eq!(repl.buffer(), "é€😀");
+		feed(&mut repl, b"\x1b[D\x7f").await; // backspace removes a whole char
+		assert_eq!(repl.buffer(), "é😀");
+		assert_eq!(repl.cursor, "é".len());
+	}
+
+	#[tokio::test]
+	async fn invalid_utf8_becomes_replacement_chars() {
+		let (mut repl, _rx) = repl();
+		feed(&mut repl, &[0xFF, b'a', 0x80, 0xC3, b'b', 0xE2, 0x82, 0xC3, 0xA9]).await;
+		assert_eq!(repl.buffer(), "\u{FFFD}a\u{FFFD}\u{FFFD}b\u{FFFD}é");
+	}
+
+	fn texts(rx: &mut mpsc::UnboundedReceiver<Output>) -> String {
+		let mut out = String::new();
+		while let Ok(msg) = rx.try_recv() {
+			if let Output::Text(txt) = msg {
+				out.push_str(&txt);
+			}
+		}
+		out
+	}
+
+	#[tokio::test]
+	async fn output_and_time_switch_display() {
+		let (mut repl, mut rx) = repl();
+		feed(&mut repl, b"return 1 + 1\r").await;
+		assert_eq!(texts(&mut rx), "=(integer) 2\n");
+		feed(&mut repl, b".output json\r.time on\r").await;
+		texts(&mut rx);
+		feed(&mut repl, b"return { a = 1 }\r").await;
+		let out = texts(&mut rx);
+		assert!(out.starts_with("{\"a\":1}\n~ took "), "{}", out);
+		feed(&mut repl, b".output\r.time off\r.time\r.output yaml\r").await;
+		assert_eq!(texts(&mut rx), "~ output is json\n~ timing is off\n! usage: .output json|text\n");
+	}
+
+	#[tokio::test]
+	async fn history_lists_last_entries() {
+		let (mut repl, mut rx) = repl();
+		feed(&mut repl, b"a = 1\rb = 2\r").await;
+		texts(&mut rx);
+		feed(&mut repl, b".history 2\r").await;
+		assert_eq!(texts(&mut rx), "    2  b = 2\n    3  .history 2\n");
+		feed(&mut repl, b".history many\r").await;
+		assert!(texts(&mut rx).starts_with("! invalid count 'many'"));
+	}
+
+	#[tokio::test]
+	async fn save_and_load_roundtrip() {
+		let path = std::env::temp_dir().join(format!("cordy-repl-test-{}.lua", std::process::id()));
+		let path = path.to_str().unwrap();
+		let (mut saving, mut rx) = repl();
+		feed(&mut saving, b"x = 20\rfunction f()\rreturn x * 2\rend\r").await;
+		feed(&mut saving, format!(".save {}\r", path).as_bytes()).await;
+		assert!(texts(&mut rx).contains(&format!("~ saved 2 entries to {}", path)));
+
+		let (mut other, mut other_rx) = repl();
+		other.state = LuaState::run("repl-load-test".into(), Some(other.console.clone())).unwrap();
+		feed(&mut other, format!(".load {}\rreturn f()\r", path).as_bytes()).await;
+		std::fs::remove_file(path).unwrap();
+		assert_eq!(texts(&mut other_rx), "=(integer) 40\n");
+	}
+
+	#[tokio::test]
+	async fn reset_refuses_shared_states() {
+		let (mut repl, mut rx) = repl();
+		repl.state = shared("repl-shared-test").unwrap();
+		feed(&mut repl, b"kept = true\r.reset\rreturn kept\r").await;
+		let out = texts(&mut rx);
+		assert!(out.contains("! state 'repl-shared-test' is shared"), "{}", out);
+		assert!(out.ends_with("=(boolean) true\n"), "{}", out);
+
+		repl.state = LuaState::run("repl-reset-test".into(), Some(repl.console.clone())).unwrap();
+		feed(&mut repl, b"gone = true\r.reset\rreturn gone\r").await;
+		let out = texts(&mut rx);
+		assert!(out.contains("~ state 'repl-reset-test' reset"), "{}", out);
+		assert!(out.ends_with("=(nil) nil\n"), "{}", out);
+	}
+
+	#[tokio::test]
+	async fn quit_closes_the_repl() {
+		let (mut repl, mut rx) = repl();
+		assert!(!repl.closed());
+		feed(&mut repl, b".quit\r").await;
+		assert!(repl.closed());
+		assert_eq!(texts(&mut rx), "~ bye\n");
+	}
 }
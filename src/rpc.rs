@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::{auth::token_matches, budget::Limits, repl::VERSIONTEXT, state::{LuaStateHandle, Outcome, shared}, transcript::Transcript};
+
+/// first line sent to rpc clients, before any request
+#[derive(Serialize)]
+struct Hello {
+	cordy: &'static str,
+	version: &'static str,
+	pid: u32,
+	/// if set, the first line sent must be an `Auth` request
+	auth: bool,
+}
+
+#[derive(Deserialize)]
+struct Auth {
+	token: String,
+}
+
+#[derive(Deserialize)]
+struct Request {
+	#[serde(default)]
+	id: Value,
+	code: String,
+}
+
+#[derive(Serialize, Default)]
+struct Response {
+	id: Value,
+	results: Vec<Value>,
+	error: Option<String>,
+	logs: Vec<String>,
+}
+
+/// lua output not tied to any request (i.e. from background code)
+#[derive(Serialize)]
+struct Event {
+	event: &'static str,
+	text: String,
+}
+
+/// line based json protocol: clients send one `{id, code}` request per line and
+/// get one `{id, results, error, logs}` response per line, in order
+pub struct RpcSession {
+	state: LuaStateHandle,
+	limits: Limits,
+	transcript: Option<Transcript>,
+}
+
+impl RpcSession {
+	pub fn new(state: LuaStateHandle, limits: Limits, transcript: Option<Transcript>) -> Self {
+		RpcSession { state, limits, transcript }
+	}
+
+	pub fn hello(auth: bool) -> String {
+		line(&Hello { cordy: VERSIONTEXT, version: env!("CARGO_PKG_VERSION"), pid: std::process::id(), auth })
+	}
+
+	/// returns the line to send back, and whether the client is authenticated
+	pub fn authenticate(request: &str, token: &str) -> (String, bool) {
+		match serde_json::from_str::<Auth>(request) {
+			Ok(auth) if token_matches(&auth.token, token) => (line(&serde_json::json!({ "auth": true })), true),
+			Ok(_) => (error_line("authentication failed"), false),
+			Err(e) => (error_line(&format!("expected auth request: {}", e)), false),
+		}
+	}
+
+	/// sent instead of serving a client, before closing its connection
+	pub fn refusal(msg: &str) -> String {
+		error_line(msg)
+	}
+
+	pub fn event(&mut self, text: String) -> String {
+		if let Some(transcript) = self.transcript.as_mut() {
+			transcript.output(&text);
+		}
+		line(&Event { event: "log", text: text.trim_end().to_string() })
+	}
+
+	pub async fn handle(&mut self, request: &str) -> Result<String, mlua::Error> {
+		let req : Request = match serde_json::from_str(request) {
+			Ok(r) => r,
+			Err(e) => return Ok(error_line(&format!("invalid request: {}", e))),
+		};
+
+		if let Some(transcript) = self.transcript.as_mut() {
+			transcript.input(&req.code);
+		}
+
+		// every request gets its own console, to collect its logs
+		let (console_tx, mut console_rx) = mpsc::unbounded_channel();
+		let evaluation = self.state.eval_json(req.code, console_tx.into(), self.limits.clone()).await?;
+		let mut res = Response { id: req.id, ..Default::default() };
+		while let Ok(out) = console_rx.try_recv() {
+			res.logs.push(out.into_string().trim_end().to_string());
+		}
+
+		match evaluation.outcome {
+			Outcome::Json(values) => res.results = values,
+			Outcome::Values(values) => res.results = values.into_iter().map(|(_t, v)| v.into()).collect(),
+			Outcome::Incomplete => res.error = Some("incomplete input".into()),
+			Outcome::Failed(e) => res.error = Some(e),
+		}
+
+		if let Some(name) = evaluation.attach {
+			match shared(&name) {
+				Ok(state) => self.state = state,
+				Err(e) => res.error = Some(format!("could not attach to state '{}': {}", name, e)),
+			}
+		}
+
+		if let Some(transcript) = self.transcript.as_mut() {
+			for log in res.logs.iter() {
+				transcript.output(log);
+			}
+			for value in res.results.iter() {
+				transcript.output(&value.to_string());
+			}
+			if let Some(e) = &res.error {
+				transcript.output(&format!("! {}", e));
+			}
+			if let Some(on) = evaluation.record {
+				transcript.set_enabled(on);
+			}
+		}
+
+		Ok(line(&res))
+	}
+}
+
+fn error_line(msg: &str) -> String {
+	line(&Response { error: Some(msg.into()), ..Default::default() })
+}
+
+fn line<T: Serialize>(msg: &T) -> String {
+	let mut out = serde_json::to_string(msg)
+		.unwrap_or_else(|e| format!("{{\"error\":\"could not serialize response: {}\"}}", e));
+	out.push('\n');
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{json, Value};
+
+	use crate::{budget::Limits, state::LuaState};
+
+	use super::RpcSession;
+
+	fn session(name: &str) -> RpcSession {
+		RpcSession::new(LuaState::run(name.into(), None).unwrap(), Limits::default(), None)
+	}
+
+	fn parse(line: &str) -> Value {
+		assert!(line.ends_with('\n'));
+		serde_json::from_str(line).unwrap()
+	}
+
+	#[tokio::test]
+	async fn requests_get_results_errors_and_logs() {
+		let mut rpc = session("rpc-test");
+		let res = parse(&rpc.handle(r#"{"id": 1, "code": "return 1, 'two', { three = 3 }"}"#).await.unwrap());
+		assert_eq!(res, json!({ "id": 1, "results": [1, "two", { "three": 3 }], "error": null, "logs": [] }));
+
+		let res = parse(&rpc.handle(r#"{"id": "b", "code": "log('hello') error('boom', 0)"}"#).await.unwrap());
+		assert_eq!(res["id"], "b");
+		assert!(res["error"].as_str().unwrap().starts_with("runtime error: boom"));
+		assert_eq!(res["logs"], json!(["hello"]));
+
+		let res = parse(&rpc.handle(r#"{"code": "for i = 1, 2 do"}"#).await.unwrap());
+		assert_eq!(res["id"], Value::Null);
+		assert_eq!(res["error"], "incomplete input");
+	}
+
+	#[tokio::test]
+	async fn bad_json_is_reported_not_evaluated() {
+		let mut rpc = session("rpc-json-test");
+		let res = parse(&rpc.handle("return 1").await.unwrap());
+		assert!(res["error"].as_str().unwrap().starts_with("invalid request"));
+		let res = parse(&rpc.handle(r#"{"id": 1}"#).await.unwrap());
+		assert!(res["error"].as_str().unwrap().contains("code"));
+	}
+
+	#[test]
+	fn authentication() {
+		assert_eq!(parse(&RpcSession::hello(true))["auth"], true);
+		let (reply, ok) = RpcSession::authenticate(r#"{"token": "secret"}"#, "secret");
+		assert!(ok);
+		assert_eq!(parse(&reply), json!({ "auth": true }));
+		let (reply, ok) = RpcSession::authenticate(r#"{"token": "guess"}"#, "secret");
+		assert!(!ok);
+		assert_eq!(parse(&reply)["error"], "authentication failed");
+		let (reply, ok) = RpcSession::authenticate(r#"{"id": 1, "code": "return 1"}"#, "secret");
+		assert!(!ok);
+		assert!(parse(&reply)["error"].as_str().unwrap().starts_with("expected auth request"));
+	}
+}
@@ -0,0 +1,370 @@
+use std::{collections::BTreeMap, path::PathBuf, rc::Rc, sync::Mutex};
+
+use mlua::{Lua, LuaOptions, MultiValue, StdLib};
+use tokio::{sync::{mpsc, oneshot, Mutex as AsyncMutex}, task::LocalSet};
+use tracing::{debug, error, info};
+
+use crate::{autorun::autorun, budget::{self, Limits, limited}, complete::completions, console::{Console, Output}, helpers::pretty_lua, tools::{register_builtin_fn, format::GLOBAL_CONSOLE, tasks::{self, Tasks}}};
+
+/// state created at startup, where autorun scripts are run
+pub const MAIN_STATE : &str = "main";
+
+/// named registry value set by lua code asking to switch its session to another state
+pub const ATTACH_KEY : &str = "CORDY_ATTACH";
+
+/// named registry value set by lua code asking to pause or resume session recording
+pub const RECORD_KEY : &str = "CORDY_RECORD";
+
+/// named states which live as long as the process, sessions can attach to them
+static SHARED: Mutex<BTreeMap<String, LuaStateHandle>> = Mutex::new(BTreeMap::new());
+
+pub enum Outcome {
+	/// type name and pretty printed value of each result
+	Values(Vec<(&'static str, String)>),
+	/// each result serialized, or pretty printed if it can't be
+	Json(Vec<serde_json::Value>),
+	Incomplete,
+	Failed(String),
+}
+
+pub struct Evaluation {
+	pub outcome: Outcome,
+	/// name of the state this session asked to attach to, if any
+	pub attach: Option<String>,
+	/// whether this session asked to pause or resume its transcript
+	pub record: Option<bool>,
+}
+
+enum Request {
+	Eval { code: String, console: Console, json: bool, limits: Limits, reply: oneshot::Sender<Evaluation> },
+	Script { path: PathBuf, reply: oneshot::Sender<()> },
+	Complete { word: String, reply: oneshot::Sender<Vec<String>> },
+}
+
+/// a lua state living on its own thread, driven by requests sent through its handle.
+/// when every handle is dropped, the state is closed
+pub struct LuaState {
+	name: String,
+	lua: Rc<Lua>,
+	console: Console,
+}
+
+#[derive(Clone)]
+pub struct LuaStateHandle {
+	name: String,
+	tx: mpsc::UnboundedSender<Request>,
+}
+
+impl LuaState {
+	/// lua output produced outside of any evaluation (i.e. by autorun scripts) goes to
+	/// given console, or to our logs if none is given
+	pub fn run(name: String, console: Option<Console>) -> std::io::Result<LuaStateHandle> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let thread_name = name.clone();
+
+		std::thread::Builder::new()
+			.name(format!("cordy-lua-{}", name))
+			.spawn(move || {
+				let rt = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+					Ok(rt) => rt,
+					Err(e) => {
+						error!("could not create runtime for state '{}': {}", thread_name, e);
+						return;
+					},
+				};
+				LocalSet::new().block_on(&rt, async move {
+					let console = console.unwrap_or_else(|| log_console(thread_name.clone()));
+					// debug library is needed by the budget hook, and handy in a debugging shell anyway
+					let lua = unsafe { Lua::unsafe_new_with(StdLib::ALL_SAFE | StdLib::DEBUG, LuaOptions::default()) };
+					let state = LuaState { name: thread_name, lua: Rc::new(lua), console };
+					state.work(rx).await
+				});
+			})?;
+
+		Ok(LuaStateHandle { name, tx })
+	}
+
+	async fn work(self, mut rx: mpsc::UnboundedReceiver<Request>) {
+		if let Err(e) = register_builtin_fn(&self.lua, self.console.clone()) {
+			error!("could not prepare runtime environment for state '{}': {}", self.name, e);
+		}
+
+		let (jobs_tx, mut jobs_rx) = mpsc::unbounded_channel();
+		self.lua.set_app_data(Tasks::new(jobs_tx));
+
+		// console global and session requests in the registry belong to one evaluation at a
+		// time: sessions sharing this state take turns, a slow eval holds back the others
+		let turn = Rc::new(AsyncMutex::new(()));
+
+		loop {
+			let req = tokio::select! {
+				req = rx.recv() => match req {
+					Some(req) => req,
+					None => break,
+				},
+				Some(job) = jobs_rx.recv() => {
+					tokio::task::spawn_local(tasks::run(self.lua.clone(), job));
+					continue;
+				},
+			};
+			match req {
+				Request::Eval { code, console, json, limits, reply } => {
+					let lua = self.lua.clone();
+					let default = self.console.clone();
+					let turn = turn.clone();
+					tokio::task::spawn_local(async move {
+						let _turn = turn.lock().await;
+						let res = eval(&lua, &code, console, default, json, &limits).await;
+						if reply.send(res).is_err() {
+							debug!("session left before evaluation completed");
+						}
+					});
+				},
+				Request::Script { path, reply } => {
+					let lua = self.lua.clone();
+					let turn = turn.clone();
+					// takes its turn like evaluations do, so it still completes before later requests
+					tokio::task::spawn_local(async move {
+						let _turn = turn.lock().await;
+						if let Err(e) = autorun(&lua, &path).await {
+							error!("could not run scripts from {}: {}", path.display(), e);
+						}
+						let _ = reply.send(());
+					});
+				},
+				Request::Complete { word, reply } => {
+					let _ = reply.send(completions(&self.lua, &word));
+				},
+			}
+		}
+
+		debug!("state '{}' closed", self.name);
+	}
+}
+
+async fn eval(lua: &Lua, code: &str, console: Console, default: Console, json: bool, limits: &Limits) -> Evaluation {
+	// log() and friends should reach whoever is evaluating
+	if let Err(e) = lua.globals().set(GLOBAL_CONSOLE, console) {
+		error!("could not set session console: {}", e);
+	}
+
+	if limits.hooked {
+		if let Err(e) = budget::install(lua) {
+			error!("could not install interrupt hook: {}", e);
+		}
+	}
+	tasks::inherit(lua, limits);
+
+	let outcome = match limited(limits, lua.load(code).eval_async::<MultiValue>()).await {
+		Err(reason) => Outcome::Failed(reason.into()),
+		Ok(Ok(values)) if json => Outcome::Json(
+			values.into_iter()
+				.map(|val| serde_json::to_value(&val).unwrap_or_else(|_| pretty_lua(lua, val).into()))
+				.collect()
+		),
+		Ok(Ok(values)) => Outcome::Values(
+			values.into_iter()
+				.map(|val| (val.type_name(), pretty_lua(lua, val)))
+				.collect()
+		),
+		Ok(Err(mlua::Error::SyntaxError { incomplete_input: true, .. })) => Outcome::Incomplete,
+		Ok(Err(e)) => Outcome::Failed(e.to_string()),
+	};
+
+	if let Err(e) = lua.globals().set(GLOBAL_CONSOLE, default) {
+		error!("could not restore state console: {}", e);
+	}
+
+	let attach = take_request::<String>(lua, ATTACH_KEY);
+	let record = take_request::<bool>(lua, RECORD_KEY);
+
+	Evaluation { outcome, attach, record }
+}
+
+/// read and clear a request left by lua code for the session in the registry
+fn take_request<'lua, T: mlua::FromLua<'lua>>(lua: &'lua Lua, key: &str) -> Option<T> {
+	let req = match lua.named_registry_value::<_, Option<T>>(key) {
+		Ok(x) => x,
+		Err(e) => {
+			error!("invalid {} request: {}", key, e);
+			None
+		},
+	};
+	if req.is_some() {
+		if let Err(e) = lua.unset_named_registry_value(key) {
+			error!("could not clear {} request: {}", key, e);
+		}
+	}
+	req
+}
+
+fn log_console(name: String) -> Console {
+	let (tx, mut rx) = mpsc::unbounded_channel::<Output>();
+	tokio::task::spawn_local(async move {
+		while let Some(out) = rx.recv().await {
+			if let Output::Text(txt) = out {
+				info!("[{}] {}", name, txt.trim_end());
+			}
+		}
+	});
+	tx.into()
+}
+
+impl LuaStateHandle {
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub async fn eval(&self, code: String, console: Console, limits: Limits) -> Result<Evaluation, mlua::Error> {
+		let (reply, rx) = oneshot::channel();
+		self.request(Request::Eval { code, console, json: false, limits, reply })?;
+		rx.await.map_err(|_| mlua::Error::RuntimeError(format!("state '{}' dropped evaluation", self.name)))
+	}
+
+	/// same as eval, but results are serialized rather than pretty printed
+	pub async fn eval_json(&self, code: String, console: Console, limits: Limits) -> Result<Evaluation, mlua::Error> {
+		let (reply, rx) = oneshot::channel();
+		self.request(Request::Eval { code, console, json: true, limits, reply })?;
+		rx.await.map_err(|_| mlua::Error::RuntimeError(format!("state '{}' dropped evaluation", self.name)))
+	}
+
+	/// run a script, or a directory of scripts, completing before any later request
+	pub async fn script(&self, path: PathBuf) -> Result<(), mlua::Error> {
+		let (reply, rx) = oneshot::channel();
+		self.request(Request::Script { path, reply })?;
+		rx.await.map_err(|_| mlua::Error::RuntimeError(format!("state '{}' dropped script", self.name)))
+	}
+
+	/// names which could complete last segment of {word}, looking at live globals
+	pub async fn complete(&self, word: String) -> Result<Vec<String>, mlua::Error> {
+		let (reply, rx) = oneshot::channel();
+		self.request(Request::Complete { word, reply })?;
+		rx.await.map_err(|_| mlua::Error::RuntimeError(format!("state '{}' dropped completion", self.name)))
+	}
+
+	fn request(&self, req: Request) -> Result<(), mlua::Error> {
+		self.tx.send(req)
+			.map_err(|_| mlua::Error::RuntimeError(format!("state '{}' is closed", self.name)))
+	}
+}
+
+/// get a named shared state, creating it if missing
+pub fn shared(name: &str) -> std::io::Result<LuaStateHandle> {
+	let mut states = SHARED.lock().expect("shared states lock is poisoned");
+	if let Some(state) = states.get(name) {
+		return Ok(state.clone());
+	}
+	let state = LuaState::run(name.to_string(), None)?;
+	states.insert(name.to_string(), state.clone());
+	Ok(state)
+}
+
+pub fn shared_names() -> Vec<String> {
+	SHARED.lock().expect("shared states lock is poisoned").keys().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn console() -> (Console, mpsc::UnboundedReceiver<Output>) {
+		let (tx, rx) = mpsc::unbounded_channel();
+		(Console::from(tx), rx)
+	}
+
+	fn texts(rx: &mut mpsc::UnboundedReceiver<Output>) -> Vec<String> {
+		let mut out = vec![];
+		while let Ok(msg) = rx.try_recv() {
+			if let Output::Text(txt) = msg {
+				out.push(txt);
+			}
+		}
+		out
+	}
+
+	#[tokio::test]
+	async fn concurrent_evals_keep_their_console_and_requests() {
+		let (default, _rx) = console();
+		let state = LuaState::run("state-test".into(), Some(default)).unwrap();
+		let (a, mut a_rx) = console();
+		let (b, mut b_rx) = console();
+		let slow = state.eval("sleep(0.05) log('from a') attach('elsewhere')".into(), a, Limits::default());
+		let fast = state.eval("log('from b') record(false)".into(), b, Limits::default());
+		let (slow, fast) = tokio::join!(slow, fast);
+		let (slow, fast) = (slow.unwrap(), fast.unwrap());
+		assert_eq!(slow.attach.as_deref(), Some("elsewhere"));
+		assert_eq!(slow.record, None);
+		assert_eq!(fast.attach, None);
+		assert_eq!(fast.record, Some(false));
+		assert!(texts(&mut a_rx).iter().any(|t| t.contains("from a")));
+		let b_out = texts(&mut b_rx);
+		assert!(b_out.iter().any(|t| t.contains("from b")));
+		assert!(!b_out.iter().any(|t| t.contains("from a")));
+	}
+
+	#[tokio::test]
+	async fn jit_stays_on_until_hooked_limits_are_used() {
+		let (default, _rx) = console();
+		let state = LuaState::run("jit-test".into(), Some(default.clone())).unwrap();
+		let status = |limits: Limits| {
+			let (state, default) = (state.clone(), default.clone());
+			async move {
+				match state.eval("jit.status()".into(), default, limits).await.unwrap().outcome {
+					Outcome::Values(vals) => vals[0].1.clone(),
+					_ => panic!("unexpected outcome"),
+				}
+			}
+		};
+		assert_eq!(status(Limits::default()).await, "true");
+		assert_eq!(status(Limits { hooked: true, ..Default::default() }).await, "false");
+		assert_eq!(status(Limits::default()).await, "false");
+	}
+
+	#[tokio::test]
+	async fn hooked_limits_stop_tight_loops() {
+		let (default, _rx) = console();
+		let state = LuaState::run("hook-test".into(), Some(default.clone())).unwrap();
+		let spin = |limits: Limits| {
+			let (state, default) = (state.clone(), default.clone());
+			async move {
+				match state.eval("while true do end".into(), default, limits).await.unwrap().outcome {
+					Outcome::Failed(reason) => reason,
+					_ => panic!("unexpected outcome"),
+				}
+			}
+		};
+		let timeout = Limits { timeout: Some(std::time::Duration::from_millis(100)), hooked: true, ..Default::default() };
+		assert_eq!(spin(timeout).await, "time budget exceeded");
+		let counted = Limits { instructions: Some(100_000), hooked: true, ..Default::default() };
+		assert_eq!(spin(counted).await, "instruction budget exceeded");
+
+		let interruptible = Limits { hooked: true, ..Default::default() };
+		let interrupt = interruptible.interrupt.clone();
+		let trigger = async {
+			tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			while !interrupt.trigger() {
+				tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+			}
+		};
+		let (reason, ()) = tokio::join!(spin(interruptible), trigger);
+		assert_eq!(reason, "interrupted");
+
+		// state is still usable afterwards
+		let after = state.eval("return 1 + 1".into(), default, Limits::default()).await.unwrap();
+		assert!(matches!(after.outcome, Outcome::Values(_)));
+	}
+
+	#[tokio::test]
+	async fn scripts_may_wait_at_top_level() {
+		let path = std::env::temp_dir().join(format!("cordy-script-test-{}.lua", std::process::id()));
+		std::fs::write(&path, "sleep(0.01) done = timeout(1, function() return true end)").unwrap();
+		let (default, _rx) = console();
+		let state = LuaState::run("script-test".into(), Some(default.clone())).unwrap();
+		state.script(path.clone()).await.unwrap();
+		std::fs::remove_file(&path).unwrap();
+		match state.eval("done".into(), default, Limits::default()).await.unwrap().outcome {
+			Outcome::Values(vals) => assert_eq!(vals[0].1, "true"),
+			_ => panic!("unexpected outcome"),
+		}
+	}
+}
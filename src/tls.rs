@@ -0,0 +1,120 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use tokio_rustls::{TlsAcceptor, TlsConnector, rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, server::AllowAnyAuthenticatedClient}};
+
+use crate::config::Config;
+
+/// prepare a tls acceptor if a certificate is configured. when a client ca is
+/// configured too, clients must present a certificate signed by it
+pub fn acceptor(cfg: &Config) -> Result<Option<TlsAcceptor>, String> {
+	let (cert, key) = match identity(cfg)? {
+		None => return Ok(None),
+		Some(pair) => pair,
+	};
+
+	let builder = ServerConfig::builder().with_safe_defaults();
+	let builder = match &cfg.tls_client_ca {
+		None => builder.with_no_client_auth(),
+		Some(ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+	};
+
+	let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)
+		.map_err(|e| format!("invalid tls certificate or key: {}", e))?;
+
+	Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// prepare a tls connector to dial out with, if a certificate is configured. the
+/// controller must present a certificate signed by tls_client_ca, and gets ours
+pub fn connector(cfg: &Config) -> Result<Option<TlsConnector>, String> {
+	let (cert, key) = match identity(cfg)? {
+		None => return Ok(None),
+		Some(pair) => pair,
+	};
+	let ca = cfg.tls_client_ca.as_ref()
+		.ok_or("dialing out over tls needs tls_client_ca, to verify the controller")?;
+
+	let config = ClientConfig::builder()
+		.with_safe_defaults()
+		.with_root_certificates(load_roots(ca)?)
+		.with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+		.map_err(|e| format!("invalid tls certificate or key: {}", e))?;
+
+	Ok(Some(TlsConnector::from(Arc::new(config))))
+}
+
+/// configured certificate and key. a client ca alone would mean plaintext, so it's an error
+fn identity(cfg: &Config) -> Result<Option<(&Path, &Path)>, String> {
+	match (&cfg.tls_cert, &cfg.tls_key, &cfg.tls_client_ca) {
+		(None, None, None) => Ok(None),
+		(None, None, Some(_)) => Err("tls_client_ca needs tls_cert and tls_key, or the channel would be plaintext".into()),
+		(Some(cert), Some(key), _) => Ok(Some((cert, key))),
+		_ => Err("both tls_cert and tls_key must be configured".into()),
+	}
+}
+
+fn load_roots(ca: &Path) -> Result<RootCertStore, String> {
+	let mut roots = RootCertStore::empty();
+	for cert in load_certs(ca)? {
+		roots.add(&cert).map_err(|e| format!("invalid ca {}: {}", ca.display(), e))?;
+	}
+	Ok(roots)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+	File::open(path)
+		.map(BufReader::new)
+		.map_err(|e| format!("could not open {}: {}", path.display(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+	let certs = rustls_pemfile::certs(&mut open(path)?)
+		.map_err(|e| format!("could not parse certificates from {}: {}", path.display(), e))?;
+	if certs.is_empty() {
+		return Err(format!("no certificates found in {}", path.display()));
+	}
+	Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// first pkcs8, rsa or ec private key found in given pem file
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+	let items = rustls_pemfile::read_all(&mut open(path)?)
+		.map_err(|e| format!("could not parse private key from {}: {}", path.display(), e))?;
+	for item in items {
+		match item {
+			rustls_pemfile::Item::PKCS8Key(k)
+			| rustls_pemfile::Item::RSAKey(k)
+			| rustls_pemfile::Item::ECKey(k) => return Ok(PrivateKey(k)),
+			_ => continue,
+		}
+	}
+	Err(format!("no private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+
+	#[test]
+	fn client_ca_alone_is_refused() {
+		let cfg = Config { tls_client_ca: Some(PathBuf::from("ca.pem")), ..Config::default() };
+		assert!(acceptor(&cfg).is_err());
+		assert!(connector(&cfg).is_err());
+	}
+
+	#[test]
+	fn half_identities_are_refused() {
+		let cfg = Config { tls_cert: Some(PathBuf::from("cert.pem")), ..Config::default() };
+		assert!(acceptor(&cfg).is_err());
+		let cfg = Config { tls_key: Some(PathBuf::from("key.pem")), ..Config::default() };
+		assert!(connector(&cfg).is_err());
+	}
+
+	#[test]
+	fn no_tls_without_certificates() {
+		assert!(acceptor(&Config::default()).unwrap().is_none());
+		assert!(connector(&Config::default()).unwrap().is_none());
+	}
+}
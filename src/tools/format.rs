@@ -1,7 +1,9 @@
 use iced_x86::{Decoder, DecoderOptions, IntelFormatter, Instruction, Formatter};
-use mlua::{Lua, Error, Variadic, Value, ToLua};
+use mlua::{Lua, Error, Table, Variadic, Value, FromLua, ToLua};
 
-use crate::{helpers::pretty_lua, console::Console};
+use crate::{helpers::{pretty_lua, PrettyOptions}, console::Console};
+
+use super::layout::overlay_bytes;
 
 pub const GLOBAL_CONSOLE : &str = "GLOBAL_CONSOLE";
 
@@ -9,24 +11,46 @@ pub const HELPTEXT : &str = "?> This is a complete lua repl
 ?> Make scripts or just evaluate expressions
 ?> print() will go to original process stdout, use log()
 ?> to send to this console instead
-?> Each connection will spawn a fresh repl, but only one
-?> concurrent connection is allowed
+?> Each connection will spawn a fresh repl (unless shared
+?> states are enabled), and many sessions can be connected
+?> at the same time. Use attach() to switch to a named state
+?> which outlives this connection
 ?> Some ad-hoc functions to work with affected process
 ?> are already available in this repl globals:
  >  log([arg...])                    print to console rather than stdout
- >  hexdump(bytes, [ret])            print hexdump of given {bytes} to console
+ >  hexdump(bytes, [ret])            print hexdump of given {bytes} (or struct overlay) to console
  >  exit([code])                     immediately terminate process
  >  mmap([a], l, [p], [f], [d], [o]) execute mmap syscall
  >  munmap(ptr, len)                 unmap {len} bytes at {ptr}
  >  mprotect(ptr, len, prot)         set {prot} flags from {ptr} to {ptr+len}
+ >  chain(base, offsets)             follow pointers from {base} (address or module name) adding {offsets}
+ >  struct(name, fields, [opts])     declare a struct layout, call it with an address to overlay it
+ >  addressof(overlay)               address of a struct or array overlay
  >  procmaps([ret])                  get process memory maps as string
  >  threads([ret])                   get process threads list as string
- >  read(addr, size)                 read {size} raw bytes at {addr}
- >  write(addr, bytes)               write given {bytes} at {addr}
+ >  read(addr, size)                 read {size} raw bytes at {addr}, erroring on bad memory
+ >  write(addr, bytes)               write given {bytes} at {addr}, erroring on bad memory
+ >  read_u32(addr, [endian])         read integer at {addr}, also u8 u16 u64 i8 i16 i32 i64
+ >  read_f64(addr, [endian])         read float at {addr}, also f32
+ >  read_ptr(addr)                   read pointer at {addr}
+ >  read_cstr(addr, [max])           read string at {addr} up to nul or {max} bytes
+ >  read_utf16(addr, [max], [end])   read utf16 string at {addr} up to nul or {max} units
+ >  write_u32(addr, n, [endian])     write {n} at {addr}, every read_* has a matching write_*
  >  find(ptr, len, match, [first])   search from {ptr} to {ptr+len} for {match} and return addrs
+ >  scan(sig, [opts])                find signature such as \"48 8B ?? ?5\" in {module} or mapped memory
  >  x(number, [prefix])              show hex representation of given {number}
  >  b(string)                        return array of bytes from given {string}
  >  sigsegv([set])                   get or set SIGSEGV handler state
+ >  attach(name)                     switch this session to named state {name}, creating it
+ >  states()                         list names of shared states
+ >  sleep(secs)                      wait {secs} without blocking sessions and tasks
+ >  spawn(fn, [name])                run {fn} in background, returns task id
+ >  every(secs, fn, [name])          run {fn} in background every {secs}, returns task id
+ >  timeout(secs, fn, [arg...])      call {fn}, failing if it takes more than {secs}
+ >  tasks()                          list background tasks
+ >  cancel(id)                       stop background task {id}
+ >  printopts([opts])                get or set {precision, depth, indent, hex} of printed values
+ >  record(on)                       pause or resume the transcript of this session
  >  help()                           print these messages
 ";
 
@@ -41,7 +65,7 @@ pub fn lua_log(lua: &Lua, values: Variadic<Value>) -> Result<usize, Error> {
 	let mut out = String::new();
 	let console : Console = lua.globals().get(GLOBAL_CONSOLE)?;
 	for value in values {
-		out.push_str(&pretty_lua(value));
+		out.push_str(&pretty_lua(lua, value));
 		out.push(' ');
 	}
 	out.push('\n');
@@ -50,7 +74,33 @@ pub fn lua_log(lua: &Lua, values: Variadic<Value>) -> Result<usize, Error> {
 	Ok(size)
 }
 
-pub fn lua_hexdump(lua: &Lua, (bytes, ret): (Vec<u8>, Option<bool>)) -> Result<Value, Error> {
+/// update pretty printing options with given fields, returns current ones
+pub fn lua_printopts<'lua>(lua: &'lua Lua, opts: Option<Table<'lua>>) -> Result<Table<'lua>, Error> {
+	let mut current = lua.app_data_ref::<PrettyOptions>().map(|o| o.clone()).unwrap_or_default();
+	if let Some(opts) = opts {
+		if let Some(precision) = opts.get::<_, Option<i64>>("precision")? {
+			// negative precision goes back to shortest representation
+			current.precision = usize::try_from(precision).ok();
+		}
+		if let Some(depth) = opts.get::<_, Option<usize>>("depth")? { current.depth = depth; }
+		if let Some(indent) = opts.get::<_, Option<usize>>("indent")? { current.indent = indent; }
+		if let Some(hex) = opts.get::<_, Option<bool>>("hex")? { current.hex = hex; }
+		lua.set_app_data(current.clone());
+	}
+	let out = lua.create_table()?;
+	out.set("precision", current.precision)?;
+	out.set("depth", current.depth)?;
+	out.set("indent", current.indent)?;
+	out.set("hex", current.hex)?;
+	Ok(out)
+}
+
+/// struct and array overlays get their memory dumped
+pub fn lua_hexdump<'lua>(lua: &'lua Lua, (data, ret): (Value<'lua>, Option<bool>)) -> Result<Value<'lua>, Error> {
+	let bytes = match data {
+		Value::UserData(ud) => overlay_bytes(&ud)?,
+		other => Vec::<u8>::from_lua(other, lua)?,
+	};
 	if ret.unwrap_or(false) {
 		return Ok(pretty_hex::simple_hex(&bytes).to_lua(lua)?);
 	}
@@ -0,0 +1,493 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mlua::{Lua, Error, Value, Table, AnyUserData, UserData, UserDataMethods, MetaMethod, FromLua, ToLua};
+
+use super::memory::{Endian, endian, read_bytes, read_int, read_ptr, read_uint, write_bytes, write_uint, fits};
+
+/// arrays longer than this get cut when displayed
+const MAX_SHOWN : usize = 8;
+
+/// layouts declared with struct() in a lua state, by name, so pointers can refer
+/// to layouts declared later (or to their own)
+#[derive(Default)]
+pub struct Layouts(HashMap<String, Layout>);
+
+#[derive(Clone)]
+enum Kind {
+	Int { size: usize, signed: bool },
+	Float(usize),
+	Char,
+	Ptr,
+	/// pointer to a named layout, resolved when followed
+	Pointer(String),
+	Struct(Layout),
+	Pad,
+}
+
+impl Kind {
+	fn parse(value: Value) -> Result<Kind, Error> {
+		let name = match value {
+			Value::String(s) => s.to_str()?.to_string(),
+			Value::UserData(ud) => return Ok(Kind::Struct(ud.borrow::<Layout>()?.clone())),
+			other => return Err(Error::RuntimeError(format!("invalid field type {:?}", other))),
+		};
+		Ok(match name.as_str() {
+			"u8"  => Kind::Int { size: 1, signed: false },
+			"u16" => Kind::Int { size: 2, signed: false },
+			"u32" => Kind::Int { size: 4, signed: false },
+			"u64" => Kind::Int { size: 8, signed: false },
+			"i8"  => Kind::Int { size: 1, signed: true },
+			"i16" => Kind::Int { size: 2, signed: true },
+			"i32" => Kind::Int { size: 4, signed: true },
+			"i64" => Kind::Int { size: 8, signed: true },
+			"f32" | "float" => Kind::Float(4),
+			"f64" | "double" => Kind::Float(8),
+			"char" => Kind::Char,
+			"ptr" => Kind::Ptr,
+			"pad" => Kind::Pad,
+			_ => match name.strip_prefix('*') {
+				Some(target) if !target.is_empty() => Kind::Pointer(target.to_string()),
+				_ => return Err(Error::RuntimeError(format!("unknown field type '{}'", name))),
+			},
+		})
+	}
+
+	fn size(&self) -> usize {
+		match self {
+			Kind::Int { size, .. } | Kind::Float(size) => *size,
+			Kind::Char | Kind::Pad => 1,
+			Kind::Ptr | Kind::Pointer(_) => std::mem::size_of::<usize>(),
+			Kind::Struct(layout) => layout.0.size,
+		}
+	}
+
+	fn align(&self) -> usize {
+		match self {
+			Kind::Struct(layout) => layout.0.align,
+			other => other.size(),
+		}
+	}
+
+	/// bytes taken by {count} of these
+	fn span(&self, count: usize) -> Result<usize, Error> {
+		self.size().checked_mul(count)
+			.ok_or_else(|| Error::RuntimeError(format!("{} {} elements don't fit in memory", count, self.name())))
+	}
+
+	fn name(&self) -> String {
+		match self {
+			Kind::Int { size, signed } => format!("{}{}", if *signed { "i" } else { "u" }, size * 8),
+			Kind::Float(size) => format!("f{}", size * 8),
+			Kind::Char => "char".into(),
+			Kind::Ptr => "ptr".into(),
+			Kind::Pointer(target) => format!("*{}", target),
+			Kind::Struct(layout) => layout.0.name.clone(),
+			Kind::Pad => "pad".into(),
+		}
+	}
+}
+
+#[derive(Clone)]
+struct Field {
+	name: String,
+	kind: Kind,
+	/// makes this field an array
+	count: Option<usize>,
+	offset: usize,
+}
+
+impl Field {
+	/// where this field lives in a struct at {base}
+	fn addr(&self, base: usize) -> Result<usize, Error> {
+		base.checked_add(self.offset)
+			.ok_or_else(|| Error::RuntimeError(format!("field '{}' of a struct at 0x{:X} is past the end of memory", self.name, base)))
+	}
+
+	fn type_name(&self) -> String {
+		match self.count {
+			Some(n) => format!("{}[{}]", self.kind.name(), n),
+			None => self.kind.name(),
+		}
+	}
+}
+
+struct Def {
+	name: String,
+	fields: Vec<Field>,
+	size: usize,
+	align: usize,
+	order: Endian,
+}
+
+/// struct declared from lua, call it with an address to overlay it there
+#[derive(Clone)]
+pub struct Layout(Arc<Def>);
+
+impl Layout {
+	fn field(&self, name: &str) -> Option<&Field> {
+		self.0.fields.iter().find(|f| f.name == name && !matches!(f.kind, Kind::Pad))
+	}
+}
+
+impl UserData for Layout {
+	fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+		methods.add_meta_method(MetaMethod::Call, |_, this, addr: usize| Ok(View { layout: this.clone(), addr }));
+		methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.0.size));
+		methods.add_meta_method(MetaMethod::Index, |_, this, name: String| Ok(this.field(&name).map(|f| f.offset)));
+		methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+			let mut out = format!("struct {} ({} bytes, align {}) {{\n", this.0.name, this.0.size, this.0.align);
+			for field in this.0.fields.iter().filter(|f| !matches!(f.kind, Kind::Pad)) {
+				out.push_str(&format!("  0x{:02X} {}: {}\n", field.offset, field.name, field.type_name()));
+			}
+			out.push('}');
+			Ok(out)
+		});
+	}
+}
+
+/// a layout overlaid on live memory: reading fields reads memory, and assigning writes it
+pub struct View {
+	layout: Layout,
+	addr: usize,
+}
+
+impl View {
+	fn field(&self, name: &str) -> Result<&Field, Error> {
+		self.layout.field(name)
+			.ok_or_else(|| Error::RuntimeError(format!("no field '{}' in struct {}", name, self.layout.0.name)))
+	}
+
+	fn dump(&self, lua: &Lua, out: &mut String, base: usize, level: usize) {
+		let pad = "  ".repeat(level + 1);
+		for field in self.layout.0.fields.iter().filter(|f| !matches!(f.kind, Kind::Pad)) {
+			let offset = base + field.offset; // relative to the outer struct, which fits in memory
+			out.push_str(&format!("{}0x{:02X} {}: {}", pad, offset, field.name, field.type_name()));
+			match (&field.kind, field.count, field.addr(self.addr)) {
+				(Kind::Struct(layout), None, Ok(addr)) => {
+					out.push_str(" {\n");
+					View { layout: layout.clone(), addr }.dump(lua, out, offset, level + 1);
+					out.push_str(&format!("{}}}\n", pad));
+				},
+				_ => out.push_str(&format!(" = {}\n", show_field(lua, field, self.addr, self.layout.0.order))),
+			}
+		}
+	}
+}
+
+impl UserData for View {
+	fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+		methods.add_meta_method(MetaMethod::Index, |lua, this, name: String| {
+			get_field(lua, this.field(&name)?, this.addr, this.layout.0.order)
+		});
+		methods.add_meta_method(MetaMethod::NewIndex, |lua, this, (name, value): (String, Value)| {
+			set_field(lua, this.field(&name)?, this.addr, this.layout.0.order, value)
+		});
+		methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.layout.0.size));
+		methods.add_meta_method(MetaMethod::ToString, |lua, this, ()| {
+			let mut out = format!("{} @ 0x{:X} ({} bytes) {{\n", this.layout.0.name, this.addr, this.layout.0.size);
+			this.dump(lua, &mut out, 0, 0);
+			out.push('}');
+			Ok(out)
+		});
+	}
+}
+
+/// array field of a struct, indexed from 1 like lua tables
+pub struct ArrayView {
+	kind: Kind,
+	addr: usize,
+	count: usize,
+	order: Endian,
+}
+
+impl ArrayView {
+	fn element(&self, index: usize) -> Result<usize, Error> {
+		if !(1..=self.count).contains(&index) {
+			return Err(Error::RuntimeError(format!("index {} out of bounds 1..{}", index, self.count)));
+		}
+		self.kind.span(index - 1)
+			.and_then(|off| self.addr.checked_add(off).ok_or_else(|| Error::RuntimeError(format!("element {} is past the end of memory", index))))
+	}
+}
+
+impl UserData for ArrayView {
+	fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+		methods.add_meta_method(MetaMethod::Index, |lua, this, index: usize| {
+			get_scalar(lua, &this.kind, this.element(index)?, this.order)
+		});
+		methods.add_meta_method(MetaMethod::NewIndex, |lua, this, (index, value): (usize, Value)| {
+			set_scalar(lua, &this.kind, this.element(index)?, this.order, value)
+		});
+		methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.count));
+		methods.add_meta_method(MetaMethod::ToString, |lua, this, ()| {
+			Ok(format!("{}[{}] @ 0x{:X} {}", this.kind.name(), this.count, this.addr, show_array(lua, this)))
+		});
+	}
+}
+
+fn get_field<'lua>(lua: &'lua Lua, field: &Field, base: usize, order: Endian) -> Result<Value<'lua>, Error> {
+	let addr = field.addr(base)?;
+	match (field.count, &field.kind) {
+		(Some(count), Kind::Char) => {
+			let mut bytes = read_bytes(addr, count)?;
+			if let Some(end) = bytes.iter().position(|b| *b == 0) {
+				bytes.truncate(end);
+			}
+			Ok(Value::String(lua.create_string(&bytes)?))
+		},
+		(Some(count), kind) => {
+			lua.create_userdata(ArrayView { kind: kind.clone(), addr, count, order }).map(Value::UserData)
+		},
+		(None, kind) => get_scalar(lua, kind, addr, order),
+	}
+}
+
+fn get_scalar<'lua>(lua: &'lua Lua, kind: &Kind, addr: usize, order: Endian) -> Result<Value<'lua>, Error> {
+	match kind {
+		Kind::Int { size, signed } => Ok(Value::Integer(read_int(addr, *size, *signed, order)?)),
+		Kind::Float(4) => Ok(Value::Number(f32::from_bits(read_uint(addr, 4, order)? as u32) as f64)),
+		Kind::Float(_) => Ok(Value::Number(f64::from_bits(read_uint(addr, 8, order)?))),
+		Kind::Char => Ok(Value::Integer(read_int(addr, 1, false, order)?)),
+		Kind::Ptr => read_ptr(addr)?.to_lua(lua),
+		Kind::Pointer(target) => match read_ptr(addr)? {
+			0 => Ok(Value::Nil),
+			ptr => {
+				let layout = lua.app_data_ref::<Layouts>()
+					.and_then(|l| l.0.get(target).cloned())
+					.ok_or_else(|| Error::RuntimeError(format!("unknown struct '{}'", target)))?;
+				lua.create_userdata(View { layout, addr: ptr }).map(Value::UserData)
+			},
+		},
+		Kind::Struct(layout) => lua.create_userdata(View { layout: layout.clone(), addr }).map(Value::UserData),
+		Kind::Pad => Ok(Value::Nil),
+	}
+}
+
+fn set_field(lua: &Lua, field: &Field, base: usize, order: Endian, value: Value) -> Result<(), Error> {
+	let addr = field.addr(base)?;
+	match (field.count, &field.kind) {
+		(Some(count), Kind::Char) => {
+			let txt = mlua::String::from_lua(value, lua)?;
+			// truncated if too long, nul padded otherwise
+			let mut bytes = txt.as_bytes().to_vec();
+			bytes.resize(count, 0);
+			write_bytes(addr, &bytes)
+		},
+		(Some(_), _) => Err(Error::RuntimeError(format!("can't assign array field '{}', set its elements", field.name))),
+		(None, kind) => set_scalar(lua, kind, addr, order, value),
+	}
+}
+
+fn set_scalar(lua: &Lua, kind: &Kind, addr: usize, order: Endian, value: Value) -> Result<(), Error> {
+	match kind {
+		Kind::Int { size, .. } => write_uint(addr, *size, fits(i64::from_lua(value, lua)?, *size)?, order),
+		Kind::Char => write_uint(addr, 1, fits(i64::from_lua(value, lua)?, 1)?, order),
+		Kind::Float(4) => write_uint(addr, 4, (f64::from_lua(value, lua)? as f32).to_bits() as u64, order),
+		Kind::Float(_) => write_uint(addr, 8, f64::from_lua(value, lua)?.to_bits(), order),
+		Kind::Ptr | Kind::Pointer(_) => {
+			let ptr = match value {
+				Value::Nil => 0,
+				Value::UserData(ud) => addressof(&ud)?,
+				other => usize::from_lua(other, lua)?,
+			};
+			write_bytes(addr, &ptr.to_ne_bytes())
+		},
+		Kind::Struct(layout) => Err(Error::RuntimeError(format!("can't assign whole struct {}, set its fields", layout.0.name))),
+		Kind::Pad => Err(Error::RuntimeError("can't assign padding".into())),
+	}
+}
+
+fn show_field(lua: &Lua, field: &Field, base: usize, order: Endian) -> String {
+	let res = field.addr(base).and_then(|addr| match (&field.kind, field.count) {
+		(Kind::Char, Some(_)) => get_field(lua, field, base, order).map(|v| match v {
+			Value::String(s) => format!("{:?}", String::from_utf8_lossy(s.as_bytes())),
+			_ => "?".into(),
+		}),
+		(kind, Some(count)) => Ok(show_array(lua, &ArrayView { kind: kind.clone(), addr, count, order })),
+		(kind, None) => show_scalar(lua, kind, addr, order),
+	});
+	res.unwrap_or_else(|e| format!("<{}>", e))
+}
+
+/// pointers are shown as addresses rather than followed, they may well be cyclic
+fn show_scalar(lua: &Lua, kind: &Kind, addr: usize, order: Endian) -> Result<String, Error> {
+	match kind {
+		Kind::Ptr | Kind::Pointer(_) => Ok(format!("0x{:X}", read_ptr(addr)?)),
+		Kind::Struct(layout) => Ok(format!("{} @ 0x{:X}", layout.0.name, addr)),
+		other => match get_scalar(lua, other, addr, order)? {
+			Value::Integer(n) => Ok(n.to_string()),
+			Value::Number(n) => Ok(n.to_string()),
+			_ => Ok("?".into()),
+		},
+	}
+}
+
+fn show_array(lua: &Lua, array: &ArrayView) -> String {
+	let shown = std::cmp::min(array.count, MAX_SHOWN);
+	let mut items = vec![];
+	for i in 1..=shown {
+		match array.element(i).and_then(|addr| show_scalar(lua, &array.kind, addr, array.order)) {
+			Ok(txt) => items.push(txt),
+			Err(e) => {
+				items.push(format!("<{}>", e));
+				break;
+			},
+		}
+	}
+	if array.count > shown {
+		items.push("...".into());
+	}
+	format!("[{}]", items.join(", "))
+}
+
+/// where a struct or array overlay lives
+pub fn addressof(ud: &AnyUserData) -> Result<usize, Error> {
+	if let Ok(view) = ud.borrow::<View>() {
+		return Ok(view.addr);
+	}
+	if let Ok(array) = ud.borrow::<ArrayView>() {
+		return Ok(array.addr);
+	}
+	Err(Error::RuntimeError("not a struct or array overlay".into()))
+}
+
+/// memory covered by a struct or array overlay
+pub fn overlay_bytes(ud: &AnyUserData) -> Result<Vec<u8>, Error> {
+	if let Ok(view) = ud.borrow::<View>() {
+		return read_bytes(view.addr, view.layout.0.size);
+	}
+	if let Ok(array) = ud.borrow::<ArrayView>() {
+		return read_bytes(array.addr, array.kind.span(array.count)?);
+	}
+	Err(Error::RuntimeError("not a struct or array overlay".into()))
+}
+
+/// fields are given in order as `{name, type, [count], at = offset}`, and laid out
+/// like a C compiler would, unless `packed` is set in {opts}
+pub fn lua_struct(lua: &Lua, (name, specs, opts): (String, Vec<Table>, Option<Table>)) -> Result<Layout, Error> {
+	let (packed, order) = match opts {
+		Some(opts) => (opts.get::<_, Option<bool>>("packed")?.unwrap_or(false), endian(opts.get("endian")?)?),
+		None => (false, endian(None)?),
+	};
+
+	let mut fields = vec![];
+	for spec in specs {
+		let field_name : String = spec.get(1)?;
+		let kind = Kind::parse(spec.get(2)?)?;
+		fields.push((Field { name: field_name, kind, count: spec.get(3)?, offset: 0 }, spec.get("at")?));
+	}
+	let (fields, size, align) = lay_out(&name, fields, packed)?;
+
+	let layout = Layout(Arc::new(Def { name: name.clone(), fields, size, align, order }));
+	if let Some(mut layouts) = lua.app_data_mut::<Layouts>() {
+		layouts.0.insert(name, layout.clone());
+	}
+	Ok(layout)
+}
+
+/// place {fields} one after the other, each at its explicit offset if it has one.
+/// returns them with offsets set, along with struct size and alignment
+fn lay_out(name: &str, fields: Vec<(Field, Option<usize>)>, packed: bool) -> Result<(Vec<Field>, usize, usize), Error> {
+	let too_big = || Error::RuntimeError(format!("struct {} doesn't fit in memory", name));
+	let mut out : Vec<Field> = vec![];
+	let (mut offset, mut size, mut align) = (0usize, 0usize, 1);
+	for (mut field, at) in fields {
+		if out.iter().any(|f| f.name == field.name && !matches!(f.kind, Kind::Pad)) {
+			return Err(Error::RuntimeError(format!("duplicate field '{}' in struct {}", field.name, name)));
+		}
+		let field_align = if packed { 1 } else { field.kind.align() };
+		field.offset = match at {
+			Some(at) => at,
+			None => offset.checked_next_multiple_of(field_align).ok_or_else(too_big)?,
+		};
+		align = std::cmp::max(align, field_align);
+		offset = field.kind.span(field.count.unwrap_or(1))?
+			.checked_add(field.offset)
+			.ok_or_else(too_big)?;
+		size = std::cmp::max(size, offset);
+		out.push(field);
+	}
+	let size = size.checked_next_multiple_of(align).ok_or_else(too_big)?;
+	Ok((out, size, align))
+}
+
+pub fn lua_addressof(_: &Lua, overlay: AnyUserData) -> Result<usize, Error> {
+	addressof(&overlay)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn field(name: &str, kind: Kind, count: Option<usize>) -> (Field, Option<usize>) {
+		(Field { name: name.into(), kind, count, offset: 0 }, None)
+	}
+
+	fn int(size: usize) -> Kind {
+		Kind::Int { size, signed: false }
+	}
+
+	fn offsets(fields: &[Field]) -> Vec<usize> {
+		fields.iter().map(|f| f.offset).collect()
+	}
+
+	fn layout(name: &str, fields: Vec<(Field, Option<usize>)>) -> Layout {
+		let (fields, size, align) = lay_out(name, fields, false).unwrap();
+		Layout(Arc::new(Def { name: name.into(), fields, size, align, order: Endian::Little }))
+	}
+
+	#[test]
+	fn fields_get_padded_to_their_alignment() {
+		let (fields, size, align) = lay_out("s", vec![field("a", int(1), None), field("b", int(4), None), field("c", int(2), None)], false).unwrap();
+		assert_eq!(offsets(&fields), [0, 4, 8]);
+		assert_eq!((size, align), (12, 4));
+		let (fields, size, align) = lay_out("s", vec![field("a", int(1), None), field("b", int(4), None)], true).unwrap();
+		assert_eq!(offsets(&fields), [0, 1]);
+		assert_eq!((size, align), (5, 1));
+	}
+
+	#[test]
+	fn explicit_offsets_win() {
+		let mut b = field("b", int(2), None);
+		b.1 = Some(0x10);
+		let (fields, size, _) = lay_out("s", vec![field("a", int(8), None), b, field("c", int(1), None)], false).unwrap();
+		assert_eq!(offsets(&fields), [0, 0x10, 0x12]);
+		assert_eq!(size, 0x18);
+	}
+
+	#[test]
+	fn nested_structs_bring_their_size_and_alignment() {
+		let inner = layout("inner", vec![field("x", int(1), None), field("y", int(8), None)]);
+		assert_eq!((inner.0.size, inner.0.align), (16, 8));
+		let (fields, size, align) = lay_out("outer", vec![field("tag", int(1), None), field("in", Kind::Struct(inner), None)], false).unwrap();
+		assert_eq!(offsets(&fields), [0, 8]);
+		assert_eq!((size, align), (24, 8));
+	}
+
+	#[test]
+	fn arrays_take_count_elements() {
+		let (fields, size, _) = lay_out("s", vec![field("a", int(2), Some(3)), field("b", int(4), None), field("name", Kind::Char, Some(5))], false).unwrap();
+		assert_eq!(offsets(&fields), [0, 8, 12]);
+		assert_eq!(size, 20);
+	}
+
+	#[test]
+	fn oversized_layouts_are_errors() {
+		assert!(lay_out("s", vec![field("a", int(8), Some(usize::MAX))], false).is_err());
+		let mut b = field("b", int(4), None);
+		b.1 = Some(usize::MAX - 1);
+		assert!(lay_out("s", vec![b], false).is_err());
+		assert!(lay_out("s", vec![field("a", int(1), None), field("a", int(1), None)], false).is_err());
+	}
+
+	#[test]
+	fn addresses_dont_wrap() {
+		let (fields, _, _) = lay_out("s", vec![field("a", int(1), None), field("b", int(4), None)], false).unwrap();
+		assert_eq!(fields[1].addr(0x1000).unwrap(), 0x1004);
+		assert!(fields[1].addr(usize::MAX - 1).is_err());
+		let array = ArrayView { kind: int(4), addr: usize::MAX - 7, count: 4, order: Endian::Little };
+		assert_eq!(array.element(2).unwrap(), usize::MAX - 3);
+		assert!(array.element(3).is_err());
+		assert!(array.element(0).is_err());
+	}
+}
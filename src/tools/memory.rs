@@ -1,36 +1,469 @@
-use mlua::{Lua, Error};
+use std::{fs::OpenOptions, io::{IoSlice, IoSliceMut}, os::unix::fs::FileExt};
 
-pub fn lua_read(_: &Lua, (addr, size): (usize, usize)) -> Result<Vec<u8>, Error> {
-	if size == 0 {
-		return Ok("".into());
+use mlua::{Lua, Error, FromLua, Value};
+use nix::{errno::Errno, sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec}, unistd::Pid};
+
+use procfs::process::MMPermissions;
+
+use super::{proc::{module_base, proc_maps}, scan::Signature};
+
+/// biggest single read, larger dumps should be done in pieces
+const MAX_READ : usize = 256 << 20;
+
+/// direction of a memory transfer, with the local buffer
+enum Io<'a> {
+	Read(&'a mut [u8]),
+	Write(&'a [u8]),
+}
+
+impl Io<'_> {
+	fn len(&self) -> usize {
+		match self {
+			Io::Read(buf) => buf.len(),
+			Io::Write(data) => data.len(),
+		}
 	}
-	let ptr = addr as *const u8;
-	let slice = unsafe { std::slice::from_raw_parts(ptr, size) };
-	Ok(slice.to_vec())
 }
 
-pub fn lua_write(_: &Lua, (addr, data): (usize, Vec<u8>)) -> Result<usize, Error> {
-	for (i, byte) in data.iter().enumerate() {
-		let off = (addr + i) as *mut u8;
-		unsafe { *off = *byte } ;
+/// copy memory at {addr} into {buf} going through the kernel, so that unmapped or
+/// protected pages give an error rather than a SIGSEGV
+pub fn read_into(addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+	let size = buf.len();
+	match transfer(addr, Io::Read(buf)) {
+		Ok(n) if n == size => Ok(()),
+		Ok(n) => Err(fault("read", addr, size, n)),
+		Err(e) => Err(Error::RuntimeError(format!("could not read 0x{:X}: {}", addr, e))),
+	}
+}
+
+/// read as much of {buf} as possible, returning how many bytes made it before a fault
+pub fn read_some(addr: usize, buf: &mut [u8]) -> Result<usize, Error> {
+	transfer(addr, Io::Read(buf))
+		.map_err(|e| Error::RuntimeError(format!("could not read 0x{:X}: {}", addr, e)))
+}
+
+/// {size} comes from lua: refuse silly ones rather than aborting the host on allocation failure
+pub fn read_bytes(addr: usize, size: usize) -> Result<Vec<u8>, Error> {
+	if size > MAX_READ {
+		return Err(Error::RuntimeError(format!("won't read {} bytes at once, limit is {}", size, MAX_READ)));
+	}
+	let mut buf = Vec::new();
+	buf.try_reserve_exact(size)
+		.map_err(|e| Error::RuntimeError(format!("could not allocate {} bytes: {}", size, e)))?;
+	buf.resize(size, 0);
+	read_into(addr, &mut buf)?;
+	Ok(buf)
+}
+
+/// write {data} at {addr}, failing instead of crashing on pages which aren't writable
+pub fn write_bytes(addr: usize, data: &[u8]) -> Result<(), Error> {
+	match transfer(addr, Io::Write(data)) {
+		Ok(n) if n == data.len() => Ok(()),
+		Ok(n) => Err(fault("write", addr, data.len(), n)),
+		Err(e) => Err(Error::RuntimeError(format!("could not write 0x{:X}: {}", addr, e))),
+	}
+}
+
+fn fault(op: &str, addr: usize, size: usize, done: usize) -> Error {
+	Error::RuntimeError(format!(
+		"could not {} {} bytes at 0x{:X}: fault at 0x{:X} after {} bytes",
+		op, size, addr, addr.wrapping_add(done), done
+	))
+}
+
+/// move bytes between {io} and {addr}, returning how many made it: the kernel stops
+/// at the first bad page. process_vm_* may be forbidden by seccomp or missing on old
+/// kernels, in which case /proc/self/mem is used instead
+fn transfer(addr: usize, mut io: Io) -> std::io::Result<usize> {
+	let size = io.len();
+	if addr.checked_add(size).is_none() {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "range wraps around the address space"));
+	}
+	let mut done = 0;
+	while done < size {
+		let remote = [RemoteIoVec { base: addr + done, len: size - done }];
+		let res = match &mut io {
+			Io::Read(buf) => process_vm_readv(Pid::this(), &mut [IoSliceMut::new(&mut buf[done..])], &remote),
+			Io::Write(data) => process_vm_writev(Pid::this(), &[IoSlice::new(&data[done..])], &remote),
+		};
+		match res {
+			Ok(0) | Err(Errno::EFAULT) => return Ok(done),
+			Ok(n) => done += n,
+			Err(Errno::ENOSYS) | Err(Errno::EPERM) => break,
+			Err(e) => return Err(e.into()),
+		}
+	}
+	if done == size {
+		return Ok(done);
+	}
+	through_proc_mem(addr, io, done)
+}
+
+/// fallback of transfer, starting {done} bytes in. /proc/self/mem writes through read-only
+/// pages, so writes stop where writable mappings end, like process_vm_writev would
+fn through_proc_mem(addr: usize, mut io: Io, mut done: usize) -> std::io::Result<usize> {
+	let size = match io {
+		Io::Read(_) => io.len(),
+		Io::Write(_) => done + writable(addr + done, io.len() - done)?,
+	};
+	let mem = OpenOptions::new().read(true).write(matches!(io, Io::Write(_))).open("/proc/self/mem")?;
+	while done < size {
+		let offset = (addr + done) as u64;
+		let res = match &mut io {
+			Io::Read(buf) => mem.read_at(&mut buf[done..size], offset),
+			Io::Write(data) => mem.write_at(&data[done..size], offset),
+		};
+		match res {
+			Ok(0) => break,
+			Ok(n) => done += n,
+			Err(e) if e.raw_os_error() == Some(Errno::EIO as i32) => break,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(done)
+}
+
+/// how many bytes from {addr} on, up to {len}, lie in contiguous writable mappings
+fn writable(addr: usize, len: usize) -> std::io::Result<usize> {
+	let maps = proc_maps().map_err(|e| std::io::Error::other(format!("could not obtain process maps: {}", e)))?;
+	let (start, end) = (addr as u64, (addr + len) as u64);
+	let mut reach = start;
+	for map in maps.iter() {
+		let (from, to) = map.address;
+		if to <= reach {
+			continue;
+		}
+		if from > reach || !map.perms.contains(MMPermissions::WRITE) || reach >= end {
+			break;
+		}
+		reach = to;
 	}
+	Ok((reach.min(end) - start) as usize)
+}
+
+pub fn lua_read(_: &Lua, (addr, size): (usize, usize)) -> Result<Vec<u8>, Error> {
+	read_bytes(addr, size)
+}
+
+pub fn lua_write(_: &Lua, (addr, data): (usize, Vec<u8>)) -> Result<usize, Error> {
+	write_bytes(addr, &data)?;
 	Ok(data.len())
 }
 
+/// byte order of typed accessors, native unless asked otherwise
+#[derive(Clone, Copy)]
+pub enum Endian {
+	Little,
+	Big,
+}
+
+pub fn endian(spec: Option<String>) -> Result<Endian, Error> {
+	match spec.as_deref() {
+		None | Some("native") => Ok(if cfg!(target_endian = "big") { Endian::Big } else { Endian::Little }),
+		Some("le") | Some("little") => Ok(Endian::Little),
+		Some("be") | Some("big") => Ok(Endian::Big),
+		Some(other) => Err(Error::RuntimeError(format!("invalid endianness '{}', expected le or be", other))),
+	}
+}
+
+/// read an unsigned integer {size} bytes wide (up to 8)
+pub fn read_uint(addr: usize, size: usize, order: Endian) -> Result<u64, Error> {
+	let mut buf = [0u8; 8];
+	match order {
+		Endian::Little => {
+			read_into(addr, &mut buf[..size])?;
+			Ok(u64::from_le_bytes(buf))
+		},
+		Endian::Big => {
+			read_into(addr, &mut buf[8-size..])?;
+			Ok(u64::from_be_bytes(buf))
+		},
+	}
+}
+
+/// lua integers are signed: u64 values past i64::MAX come back negative, but
+/// they keep their bits and can be written back as they are
+pub fn read_int(addr: usize, size: usize, signed: bool, order: Endian) -> Result<i64, Error> {
+	let raw = read_uint(addr, size, order)?;
+	let shift = 64 - size as u32 * 8;
+	if signed {
+		Ok(((raw << shift) as i64) >> shift)
+	} else {
+		Ok(raw as i64)
+	}
+}
+
+pub fn write_uint(addr: usize, size: usize, value: u64, order: Endian) -> Result<(), Error> {
+	match order {
+		Endian::Little => write_bytes(addr, &value.to_le_bytes()[..size]),
+		Endian::Big => write_bytes(addr, &value.to_be_bytes()[8-size..]),
+	}
+}
+
+/// accept values fitting {size} bytes either as signed or unsigned, so both -1 and 255 are a valid u8
+pub fn fits(value: i64, size: usize) -> Result<u64, Error> {
+	let bits = size as u32 * 8;
+	if bits < 64 && (value < -(1i64 << (bits - 1)) || value >= (1i64 << bits)) {
+		return Err(Error::RuntimeError(format!("{} does not fit in {} bytes", value, size)));
+	}
+	Ok(value as u64)
+}
+
+macro_rules! int_accessors {
+	($($read:ident $write:ident $size:literal $signed:literal),* $(,)?) => { $(
+		pub fn $read(_: &Lua, (addr, order): (usize, Option<String>)) -> Result<i64, Error> {
+			read_int(addr, $size, $signed, endian(order)?)
+		}
+
+		pub fn $write(_: &Lua, (addr, value, order): (usize, i64, Option<String>)) -> Result<(), Error> {
+			write_uint(addr, $size, fits(value, $size)?, endian(order)?)
+		}
+	)* };
+}
+
+int_accessors! {
+	lua_read_u8  lua_write_u8  1 false,
+	lua_read_u16 lua_write_u16 2 false,
+	lua_read_u32 lua_write_u32 4 false,
+	lua_read_u64 lua_write_u64 8 false,
+	lua_read_i8  lua_write_i8  1 true,
+	lua_read_i16 lua_write_i16 2 true,
+	lua_read_i32 lua_write_i32 4 true,
+	lua_read_i64 lua_write_i64 8 true,
+}
+
+pub fn lua_read_f32(_: &Lua, (addr, order): (usize, Option<String>)) -> Result<f64, Error> {
+	Ok(f32::from_bits(read_uint(addr, 4, endian(order)?)? as u32) as f64)
+}
+
+pub fn lua_write_f32(_: &Lua, (addr, value, order): (usize, f64, Option<String>)) -> Result<(), Error> {
+	write_uint(addr, 4, (value as f32).to_bits() as u64, endian(order)?)
+}
+
+pub fn lua_read_f64(_: &Lua, (addr, order): (usize, Option<String>)) -> Result<f64, Error> {
+	Ok(f64::from_bits(read_uint(addr, 8, endian(order)?)?))
+}
+
+pub fn lua_write_f64(_: &Lua, (addr, value, order): (usize, f64, Option<String>)) -> Result<(), Error> {
+	write_uint(addr, 8, value.to_bits(), endian(order)?)
+}
+
+pub fn read_ptr(addr: usize) -> Result<usize, Error> {
+	let mut buf = [0u8; std::mem::size_of::<usize>()];
+	read_into(addr, &mut buf)?;
+	Ok(usize::from_ne_bytes(buf))
+}
+
+pub fn lua_read_ptr(_: &Lua, addr: usize) -> Result<usize, Error> {
+	read_ptr(addr)
+}
+
+pub fn lua_write_ptr(_: &Lua, (addr, value): (usize, usize)) -> Result<(), Error> {
+	write_bytes(addr, &value.to_ne_bytes())
+}
+
+/// read {unit} sized elements until a zero one, at most {max}. goes page by page,
+/// so strings ending right before unmapped memory can still be read
+fn read_terminated(addr: usize, unit: usize, max: usize) -> Result<Vec<u8>, Error> {
+	const PAGE : usize = 0x1000;
+	let limit = max.checked_mul(unit)
+		.filter(|limit| *limit <= MAX_READ)
+		.ok_or_else(|| Error::RuntimeError(format!("won't read more than {} bytes, {} units of {} asked", MAX_READ, max, unit)))?;
+	let mut out = vec![];
+	while out.len() < limit {
+		let at = addr.checked_add(out.len())
+			.ok_or_else(|| Error::RuntimeError(format!("no terminator before the end of address space, from 0x{:X}", addr)))?;
+		let chunk = std::cmp::min(limit - out.len(), PAGE - at % PAGE);
+		let mut buf = read_bytes(at, std::cmp::max(chunk - chunk % unit, unit))?;
+		if let Some(end) = buf.chunks(unit).position(|c| c.iter().all(|b| *b == 0)) {
+			buf.truncate(end * unit);
+			out.append(&mut buf);
+			break;
+		}
+		out.append(&mut buf);
+	}
+	Ok(out)
+}
+
+/// raw bytes up to nul terminator: lua strings can hold anything
+pub fn lua_read_cstr<'lua>(lua: &'lua Lua, (addr, max): (usize, Option<usize>)) -> Result<mlua::String<'lua>, Error> {
+	let bytes = read_terminated(addr, 1, max.unwrap_or(4096))?;
+	lua.create_string(&bytes)
+}
+
+pub fn lua_write_cstr(_: &Lua, (addr, txt): (usize, mlua::String)) -> Result<usize, Error> {
+	let mut bytes = txt.as_bytes().to_vec();
+	bytes.push(0);
+	write_bytes(addr, &bytes)?;
+	Ok(bytes.len())
+}
+
+/// {max} counts utf16 units, invalid sequences get replaced
+pub fn lua_read_utf16(_: &Lua, (addr, max, order): (usize, Option<usize>, Option<String>)) -> Result<String, Error> {
+	let order = endian(order)?;
+	let bytes = read_terminated(addr, 2, max.unwrap_or(4096))?;
+	let units : Vec<u16> = bytes.chunks_exact(2)
+		.map(|c| match order {
+			Endian::Little => u16::from_le_bytes([c[0], c[1]]),
+			Endian::Big => u16::from_be_bytes([c[0], c[1]]),
+		})
+		.collect();
+	Ok(String::from_utf16_lossy(&units))
+}
+
+pub fn lua_write_utf16(_: &Lua, (addr, txt, order): (usize, String, Option<String>)) -> Result<usize, Error> {
+	let order = endian(order)?;
+	let bytes : Vec<u8> = txt.encode_utf16().chain(std::iter::once(0))
+		.flat_map(|u| match order {
+			Endian::Little => u.to_le_bytes(),
+			Endian::Big => u.to_be_bytes(),
+		})
+		.collect();
+	write_bytes(addr, &bytes)?;
+	Ok(bytes.len())
+}
+
+/// follow a pointer chain: every offset but the last is added and dereferenced, the last
+/// one is just added. returns the final address and the pointers met along the way
+pub fn lua_chain(lua: &Lua, (base, offsets): (Value, Vec<i64>)) -> Result<(usize, Vec<usize>), Error> {
+	let base = match base {
+		Value::String(name) => module_base(name.to_str()?)?,
+		other => usize::from_lua(other, lua)?,
+	};
+	chain(base, &offsets)
+}
+
+fn chain(mut addr: usize, offsets: &[i64]) -> Result<(usize, Vec<usize>), Error> {
+	let mut levels = vec![];
+	for (i, off) in offsets.iter().enumerate() {
+		let at = (addr as i64).wrapping_add(*off) as usize;
+		if i + 1 == offsets.len() {
+			addr = at;
+			break;
+		}
+		addr = match read_ptr(at) {
+			Ok(0) => return Err(Error::RuntimeError(format!("level {}: null pointer at 0x{:X}", i + 1, at))),
+			Ok(ptr) => ptr,
+			Err(e) => return Err(Error::RuntimeError(format!("level {}: {}", i + 1, e))),
+		};
+		levels.push(addr);
+	}
+	Ok((addr, levels))
+}
+
+/// exact matches of {pattern} between {start} and {start+size}
 pub fn lua_find(
 	_: &Lua, (start, size, pattern, first): (usize, usize, Vec<u8>, Option<bool>)
 ) -> Result<Vec<usize>, Error> {
-	let window = pattern.len();
-	let first_only = first.unwrap_or(false);
+	if pattern.is_empty() {
+		return Err(Error::RuntimeError("can't search for an empty pattern".into()));
+	}
+	let max = if first.unwrap_or(false) { Some(1) } else { None };
 	let mut matches = vec![];
+	Signature::exact(&pattern).scan(start, size, true, max, &mut matches)?;
+	Ok(matches)
+}
 
-	for i in 0..(size-window) {
-		let slice = unsafe { std::slice::from_raw_parts((start + i) as *const u8, window) };
-		if slice == pattern {
-			matches.push(start + i);
-			if first_only { break; }
-		}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_and_write_roundtrip() {
+		let mut buf = [0u8; 8];
+		let addr = buf.as_mut_ptr() as usize;
+		write_bytes(addr + 2, b"hey").unwrap();
+		assert_eq!(read_bytes(addr, 8).unwrap(), b"\0\0hey\0\0\0");
+		assert_eq!(buf[2], b'h');
 	}
 
-	Ok(matches)
+	#[test]
+	fn proc_mem_fallback_respects_protections() {
+		let mut buf = [0u8; 8];
+		let addr = buf.as_mut_ptr() as usize;
+		assert_eq!(through_proc_mem(addr, Io::Write(b"abcd"), 1).unwrap(), 4);
+		assert_eq!(&buf[..4], b"\0bcd");
+		let mut out = [0u8; 4];
+		assert_eq!(through_proc_mem(addr, Io::Read(&mut out), 0).unwrap(), 4);
+		assert_eq!(&out, b"\0bcd");
+
+		static READ_ONLY : [u8; 4] = *b"keep";
+		let addr = READ_ONLY.as_ptr() as usize;
+		assert_eq!(through_proc_mem(addr, Io::Write(b"lose"), 0).unwrap(), 0);
+		assert_eq!(read_bytes(addr, 4).unwrap(), b"keep");
+	}
+
+	#[test]
+	fn bad_addresses_are_errors() {
+		assert!(read_bytes(0x10, 4).is_err());
+		assert!(write_bytes(0x10, b"x").is_err());
+		assert!(read_bytes(usize::MAX - 2, 8).is_err());
+	}
+
+	#[test]
+	fn huge_reads_are_refused() {
+		let x = 0u64;
+		let err = read_bytes(&x as *const u64 as usize, 1 << 62).unwrap_err();
+		assert!(err.to_string().contains("won't read"));
+	}
+
+	#[test]
+	fn terminated_reads_stop_at_nul_or_max() {
+		let buf = *b"abc\0def\0\0\0";
+		let addr = buf.as_ptr() as usize;
+		assert_eq!(read_terminated(addr, 1, 100).unwrap(), b"abc");
+		assert_eq!(read_terminated(addr, 1, 2).unwrap(), b"ab");
+		assert_eq!(read_terminated(addr, 2, 100).unwrap(), b"abc\0def\0");
+		assert!(read_terminated(addr, 2, usize::MAX).is_err());
+		assert!(read_terminated(addr, 1, MAX_READ + 1).is_err());
+	}
+
+	#[test]
+	fn chains_follow_pointers() {
+		let target = [0u64; 4];
+		let inner = [0usize, target.as_ptr() as usize];
+		let outer = [0usize, 0, inner.as_ptr() as usize];
+		let base = outer.as_ptr() as usize;
+		let (addr, levels) = chain(base, &[16, 8, 24]).unwrap();
+		assert_eq!(addr, target.as_ptr() as usize + 24);
+		assert_eq!(levels, [inner.as_ptr() as usize, target.as_ptr() as usize]);
+	}
+
+	#[test]
+	fn chains_without_offsets_stay_put() {
+		assert_eq!(chain(0x1234, &[]).unwrap(), (0x1234, vec![]));
+		assert_eq!(chain(0x1234, &[-4]).unwrap(), (0x1230, vec![]));
+	}
+
+	#[test]
+	fn chains_accept_negative_offsets() {
+		let target = [0u8; 16];
+		let slots = [0usize, target.as_ptr() as usize];
+		let end = slots.as_ptr() as usize + 16;
+		let (addr, levels) = chain(end, &[-8, -0x10]).unwrap();
+		assert_eq!(addr, (target.as_ptr() as usize).wrapping_sub(0x10));
+		assert_eq!(levels, [target.as_ptr() as usize]);
+	}
+
+	#[test]
+	fn chains_stop_at_null_pointers() {
+		let inner = [0usize; 2];
+		let outer = [inner.as_ptr() as usize];
+		let err = chain(outer.as_ptr() as usize, &[0, 8, 0]).unwrap_err().to_string();
+		assert!(err.contains("level 2: null pointer"), "{}", err);
+	}
+
+	#[test]
+	fn partial_reads_report_fault() {
+		let page = 0x1000;
+		let map = unsafe { nix::sys::mman::mmap(
+			None, std::num::NonZeroUsize::new(2 * page).unwrap(),
+			nix::sys::mman::ProtFlags::PROT_READ | nix::sys::mman::ProtFlags::PROT_WRITE,
+			nix::sys::mman::MapFlags::MAP_PRIVATE | nix::sys::mman::MapFlags::MAP_ANON, -1, 0,
+		) }.unwrap() as usize;
+		unsafe { nix::sys::mman::munmap((map + page) as *mut _, page) }.unwrap();
+		let err = read_bytes(map + page - 4, 8).unwrap_err().to_string();
+		assert!(err.contains(&format!("fault at 0x{:X} after 4 bytes", map + page)), "{}", err);
+		unsafe { nix::sys::mman::munmap(map as *mut _, page) }.unwrap();
+	}
 }
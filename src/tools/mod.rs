@@ -1,26 +1,35 @@
 use mlua::{Lua, Error};
 use nix::sys::mman::{ProtFlags, MapFlags};
-use tokio::sync::broadcast;
 
-use crate::console::Console;
+use crate::{console::Console, helpers::PrettyOptions};
 
 use self::format::GLOBAL_CONSOLE;
 
 pub mod format;
+pub mod layout;
 pub mod memory;
+pub mod scan;
 pub mod syscall;
 pub mod proc;
+pub mod session;
+pub mod tasks;
 
 pub mod dumb;
 
 use self::dumb::*;
 use self::format::*;
+use self::layout::*;
 use self::memory::*;
 use self::proc::*;
+use self::scan::*;
+use self::session::*;
 use self::syscall::*;
+use self::tasks::*;
 
-pub fn register_builtin_fn(lua: &Lua, console: broadcast::Sender<String>) -> Result<(), Error> {
-	lua.globals().set(GLOBAL_CONSOLE, Console::from(console))?; // TODO passing it this way makes clones
+pub fn register_builtin_fn(lua: &Lua, console: Console) -> Result<(), Error> {
+	lua.globals().set(GLOBAL_CONSOLE, console)?; // TODO passing it this way makes clones
+	lua.set_app_data(PrettyOptions::default());
+	lua.set_app_data(Layouts::default());
 
 	lua.globals().set("PROT_NONE",  ProtFlags::PROT_NONE.bits())?;
 	lua.globals().set("PROT_READ",  ProtFlags::PROT_READ.bits())?;
@@ -37,6 +46,36 @@ pub fn register_builtin_fn(lua: &Lua, console: broadcast::Sender<String>) -> Res
 	lua.globals().set("read",     lua.create_function(lua_read)?)?;
 	lua.globals().set("write",    lua.create_function(lua_write)?)?;
 	lua.globals().set("find",     lua.create_function(lua_find)?)?;
+	lua.globals().set("scan",     lua.create_function(lua_scan)?)?;
+	lua.globals().set("read_u8",  lua.create_function(lua_read_u8)?)?;
+	lua.globals().set("write_u8", lua.create_function(lua_write_u8)?)?;
+	lua.globals().set("read_u16", lua.create_function(lua_read_u16)?)?;
+	lua.globals().set("write_u16", lua.create_function(lua_write_u16)?)?;
+	lua.globals().set("read_u32", lua.create_function(lua_read_u32)?)?;
+	lua.globals().set("write_u32", lua.create_function(lua_write_u32)?)?;
+	lua.globals().set("read_u64", lua.create_function(lua_read_u64)?)?;
+	lua.globals().set("write_u64", lua.create_function(lua_write_u64)?)?;
+	lua.globals().set("read_i8",  lua.create_function(lua_read_i8)?)?;
+	lua.globals().set("write_i8", lua.create_function(lua_write_i8)?)?;
+	lua.globals().set("read_i16", lua.create_function(lua_read_i16)?)?;
+	lua.globals().set("write_i16", lua.create_function(lua_write_i16)?)?;
+	lua.globals().set("read_i32", lua.create_function(lua_read_i32)?)?;
+	lua.globals().set("write_i32", lua.create_function(lua_write_i32)?)?;
+	lua.globals().set("read_i64", lua.create_function(lua_read_i64)?)?;
+	lua.globals().set("write_i64", lua.create_function(lua_write_i64)?)?;
+	lua.globals().set("read_f32", lua.create_function(lua_read_f32)?)?;
+	lua.globals().set("write_f32", lua.create_function(lua_write_f32)?)?;
+	lua.globals().set("read_f64", lua.create_function(lua_read_f64)?)?;
+	lua.globals().set("write_f64", lua.create_function(lua_write_f64)?)?;
+	lua.globals().set("read_ptr", lua.create_function(lua_read_ptr)?)?;
+	lua.globals().set("write_ptr", lua.create_function(lua_write_ptr)?)?;
+	lua.globals().set("read_cstr", lua.create_function(lua_read_cstr)?)?;
+	lua.globals().set("write_cstr", lua.create_function(lua_write_cstr)?)?;
+	lua.globals().set("read_utf16", lua.create_function(lua_read_utf16)?)?;
+	lua.globals().set("write_utf16", lua.create_function(lua_write_utf16)?)?;
+	lua.globals().set("chain",    lua.create_function(lua_chain)?)?;
+	lua.globals().set("struct",   lua.create_function(lua_struct)?)?;
+	lua.globals().set("addressof", lua.create_function(lua_addressof)?)?;
 	lua.globals().set("procmaps", lua.create_function(lua_procmaps)?)?;
 	lua.globals().set("threads",  lua.create_function(lua_threads)?)?;
 	lua.globals().set("exit",     lua.create_function(lua_exit)?)?;
@@ -44,6 +83,16 @@ pub fn register_builtin_fn(lua: &Lua, console: broadcast::Sender<String>) -> Res
 	lua.globals().set("munmap",   lua.create_function(lua_munmap)?)?;
 	lua.globals().set("mprotect", lua.create_function(lua_mprotect)?)?;
 	lua.globals().set("sigsegv",  lua.create_function(lua_catch_sigsev)?)?;
+	lua.globals().set("attach",   lua.create_function(lua_attach)?)?;
+	lua.globals().set("record",   lua.create_function(lua_record)?)?;
+	lua.globals().set("states",   lua.create_function(lua_states)?)?;
+	lua.globals().set("sleep",    lua.create_async_function(lua_sleep)?)?;
+	lua.globals().set("timeout",  lua.create_async_function(lua_timeout)?)?;
+	lua.globals().set("spawn",    lua.create_function(lua_spawn)?)?;
+	lua.globals().set("every",    lua.create_function(lua_every)?)?;
+	lua.globals().set("tasks",    lua.create_function(lua_tasks)?)?;
+	lua.globals().set("cancel",   lua.create_function(lua_cancel)?)?;
+	lua.globals().set("printopts", lua.create_function(lua_printopts)?)?;
 	lua.globals().set("help",     lua.create_function(lua_help)?)?;
 	lua.globals().set("x",        lua.create_function(lua_hex)?)?;
 	lua.globals().set("b",        lua.create_function(lua_bytes)?)?;
@@ -1,5 +1,5 @@
 use mlua::{Lua, Error, Table, Value, ToLua};
-use procfs::{process::{Status, MemoryMap, Process, MemoryMaps, Task, TasksIter}, ProcResult, ProcError};
+use procfs::{process::{Status, MemoryMap, MMapPath, Process, MemoryMaps, Task, TasksIter}, ProcResult, ProcError};
 use tracing::warn;
 
 use crate::console::Console;
@@ -26,10 +26,32 @@ fn map_table(lua: &Lua, task: MemoryMap) -> Result<Table, Error> {
 	Ok(table)
 }
 
-fn proc_maps() -> ProcResult<MemoryMaps> {
+pub fn proc_maps() -> ProcResult<MemoryMaps> {
 	Ok(Process::myself()?.maps()?)
 }
 
+/// mappings of module {name}, matched against its file name or full path
+pub fn module_maps(name: &str) -> Result<Vec<MemoryMap>, Error> {
+	let maps = proc_maps()
+		.map_err(|e| Error::RuntimeError(format!("could not obtain process maps: {}", e)))?;
+	let found : Vec<MemoryMap> = maps.into_iter()
+		.filter(|m| match &m.pathname {
+			MMapPath::Path(path) => path.as_os_str() == name || path.file_name().is_some_and(|f| f == name),
+			_ => false,
+		})
+		.collect();
+	if found.is_empty() {
+		return Err(Error::RuntimeError(format!("no module '{}' mapped", name)));
+	}
+	Ok(found)
+}
+
+/// lowest address module {name} is mapped at
+pub fn module_base(name: &str) -> Result<usize, Error> {
+	let maps = module_maps(name)?;
+	Ok(maps.iter().map(|m| m.address.0).min().unwrap_or_default() as usize)
+}
+
 pub fn lua_procmaps(lua: &Lua, ret: Option<bool>) -> Result<Value, Error> {
 	let maps = proc_maps()
 		.map_err(|e| Error::RuntimeError(
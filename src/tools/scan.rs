@@ -0,0 +1,239 @@
+use mlua::{Lua, Error, Table};
+
+use crate::budget::checkpoint;
+
+use super::{memory::read_some, proc::{module_maps, proc_maps}};
+
+/// how much memory gets copied and searched at once
+const CHUNK : usize = 0x100000;
+const PAGE : usize = 0x1000;
+
+/// byte pattern where any nibble can be a wildcard, as in "48 8B ?? ?? 89 ?5"
+pub struct Signature {
+	values: Vec<u8>,
+	masks: Vec<u8>,
+	/// horspool skip table: how far the window can move given its last byte
+	shift: [usize; 256],
+}
+
+impl Signature {
+	pub fn parse(txt: &str) -> Result<Self, Error> {
+		let (mut values, mut masks) = (vec![], vec![]);
+		for token in txt.split_whitespace() {
+			let token = if token == "?" { "??" } else { token };
+			if token.chars().count() != 2 {
+				return Err(Error::RuntimeError(format!("invalid byte '{}' in signature, expected two hex digits or ?", token)));
+			}
+			let (mut value, mut mask) = (0u8, 0u8);
+			for c in token.chars() {
+				value <<= 4;
+				mask <<= 4;
+				if c != '?' {
+					let digit = c.to_digit(16)
+						.ok_or_else(|| Error::RuntimeError(format!("invalid byte '{}' in signature, expected two hex digits or ?", token)))?;
+					value |= digit as u8;
+					mask |= 0xF;
+				}
+			}
+			values.push(value);
+			masks.push(mask);
+		}
+		if masks.iter().all(|m| *m == 0) {
+			return Err(Error::RuntimeError("signature needs at least one byte which isn't a wildcard".into()));
+		}
+		Ok(Signature::new(values, masks))
+	}
+
+	pub fn exact(bytes: &[u8]) -> Self {
+		Signature::new(bytes.to_vec(), vec![0xFF; bytes.len()])
+	}
+
+	fn new(values: Vec<u8>, masks: Vec<u8>) -> Self {
+		let len = values.len();
+		let mut shift = [len; 256];
+		for i in 0..len.saturating_sub(1) {
+			for (b, skip) in shift.iter_mut().enumerate() {
+				if b as u8 & masks[i] == values[i] {
+					*skip = len - 1 - i;
+				}
+			}
+		}
+		Signature { values, masks, shift }
+	}
+
+	fn matches(&self, window: &[u8]) -> bool {
+		window.iter().zip(self.values.iter().zip(self.masks.iter())).rev()
+			.all(|(b, (value, mask))| b & mask == *value)
+	}
+
+	/// calls {found} with offsets of matches inside {hay}, until it returns false
+	fn find_in(&self, hay: &[u8], mut found: impl FnMut(usize) -> bool) -> bool {
+		let len = self.values.len();
+		let mut pos = 0;
+		while pos + len <= hay.len() {
+			if self.matches(&hay[pos..pos+len]) && !found(pos) {
+				return false;
+			}
+			pos += self.shift[hay[pos + len - 1] as usize];
+		}
+		true
+	}
+
+	/// search {size} bytes from {start} a chunk at a time, pushing match addresses in {out}
+	/// until there are {max}. when not {strict} unreadable pages are skipped rather than
+	/// failing the scan. returns false once {max} is reached
+	pub fn scan(&self, start: usize, size: usize, strict: bool, max: Option<usize>, out: &mut Vec<usize>) -> Result<bool, Error> {
+		let len = self.values.len();
+		let end = start.saturating_add(size);
+		let mut buf = vec![0u8; CHUNK + len - 1];
+		let mut at = start;
+		while at.checked_add(len).is_some_and(|stop| stop <= end) {
+			checkpoint()?; // big scans can be interrupted
+			let want = std::cmp::min(buf.len(), end - at);
+			let got = read_some(at, &mut buf[..want])?;
+			let more = self.find_in(&buf[..got], |off| {
+				out.push(at + off);
+				max.is_none_or(|max| out.len() < max)
+			});
+			if !more {
+				return Ok(false);
+			}
+			if got < want {
+				if strict {
+					return Err(Error::RuntimeError(format!("could not read 0x{:X}, scan stopped", at + got)));
+				}
+				at = match ((at + got) / PAGE * PAGE).checked_add(PAGE) {
+					Some(next) => next,
+					None => break,
+				};
+			} else if want < buf.len() {
+				break;
+			} else {
+				at += CHUNK; // next chunk overlaps this one by len - 1, so no match is cut
+			}
+		}
+		Ok(true)
+	}
+}
+
+/// ranges to scan, adjacent mappings merged so matches across them are found
+fn regions(module: Option<&str>, perms: &str) -> Result<Vec<(usize, usize)>, Error> {
+	let maps = match module {
+		Some(name) => module_maps(name)?,
+		None => proc_maps()
+			.map_err(|e| Error::RuntimeError(format!("could not obtain process maps: {}", e)))?
+			.into_iter()
+			.collect(),
+	};
+	let mut out : Vec<(usize, usize)> = vec![];
+	for map in maps.iter().filter(|m| perms.chars().all(|c| m.perms.as_str().contains(c))) {
+		let (start, end) = (map.address.0 as usize, map.address.1 as usize);
+		match out.last_mut() {
+			Some(last) if last.1 == start => last.1 = end,
+			_ => out.push((start, end)),
+		}
+	}
+	Ok(out)
+}
+
+/// {opts} can restrict the scan to a `module` or to a `start` and `size` range, pick
+/// mappings by `perms` letters (readable ones by default) and stop after `max` matches
+pub fn lua_scan(_: &Lua, (signature, opts): (String, Option<Table>)) -> Result<Vec<usize>, Error> {
+	let sig = Signature::parse(&signature)?;
+	let (mut module, mut perms, mut range, mut max) = (None, "r".to_string(), None, None);
+	if let Some(opts) = opts {
+		module = opts.get::<_, Option<String>>("module")?;
+		perms = opts.get::<_, Option<String>>("perms")?.unwrap_or(perms);
+		max = opts.get::<_, Option<usize>>("max")?;
+		if opts.get::<_, Option<bool>>("first")?.unwrap_or(false) {
+			max = Some(1);
+		}
+		if let Some(start) = opts.get::<_, Option<usize>>("start")? {
+			let size = opts.get::<_, Option<usize>>("size")?
+				.ok_or_else(|| Error::RuntimeError("scanning from start also needs a size".into()))?;
+			range = Some((start, size));
+		}
+	}
+
+	let mut matches = vec![];
+	if max == Some(0) {
+		return Ok(matches);
+	}
+	match range {
+		Some((start, size)) => { sig.scan(start, size, true, max, &mut matches)?; },
+		None => for (start, end) in regions(module.as_deref(), &perms)? {
+			if !sig.scan(start, end - start, false, max, &mut matches)? {
+				break;
+			}
+		},
+	}
+	Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn find_all(sig: &Signature, hay: &[u8]) -> Vec<usize> {
+		let mut found = vec![];
+		sig.find_in(hay, |off| { found.push(off); true });
+		found
+	}
+
+	#[test]
+	fn nibble_wildcards() {
+		let sig = Signature::parse("48 8B ?? ?5").unwrap();
+		let hay = [0x48, 0x8B, 0x00, 0x15, 0x48, 0x8B, 0xFF, 0x16, 0x48, 0x48, 0x8B, 0x01, 0xF5];
+		assert_eq!(find_all(&sig, &hay), [0, 9]);
+		let sig = Signature::parse("4? ? 8B").unwrap();
+		assert_eq!(find_all(&sig, &[0x41, 0x00, 0x8B, 0x50, 0x00, 0x8B, 0x4F, 0x4F, 0x8B]), [0, 6]);
+	}
+
+	#[test]
+	fn invalid_signatures() {
+		for txt in ["", "?? ??", "4", "123", "GG", "4x"] {
+			assert!(Signature::parse(txt).is_err(), "'{}' should not parse", txt);
+		}
+	}
+
+	#[test]
+	fn skip_table_doesnt_miss_overlapping_matches() {
+		let sig = Signature::exact(b"aab");
+		assert_eq!(find_all(&sig, b"aaab aab aaaab"), [1, 5, 11]);
+		let sig = Signature::parse("61 ?? 61").unwrap();
+		assert_eq!(find_all(&sig, b"aaaaa"), [0, 1, 2]);
+	}
+
+	#[test]
+	fn matches_straddling_chunks_are_found() {
+		let mut hay = vec![0u8; CHUNK * 2 + 16];
+		let needle = [0xDE, 0xAD, 0xBE, 0xEF];
+		let at = [CHUNK - 2, CHUNK * 2 - 1, 7];
+		for pos in at {
+			hay[pos..pos + 4].copy_from_slice(&needle);
+		}
+		let base = hay.as_ptr() as usize;
+		let mut out = vec![];
+		assert!(Signature::exact(&needle).scan(base, hay.len(), true, None, &mut out).unwrap());
+		assert_eq!(out, [base + 7, base + CHUNK - 2, base + CHUNK * 2 - 1]);
+	}
+
+	#[test]
+	fn scans_stop_at_max() {
+		let hay = [0xCCu8; 64];
+		let base = hay.as_ptr() as usize;
+		let mut out = vec![];
+		assert!(!Signature::parse("CC CC").unwrap().scan(base, hay.len(), true, Some(3), &mut out).unwrap());
+		assert_eq!(out, [base, base + 1, base + 2]);
+		let mut out = vec![];
+		assert!(Signature::parse("CC CC").unwrap().scan(base, hay.len(), true, None, &mut out).unwrap());
+		assert_eq!(out.len(), 63);
+	}
+
+	#[test]
+	fn ranges_near_the_top_of_memory_dont_wrap() {
+		let mut out = vec![];
+		assert!(Signature::exact(b"ab").scan(usize::MAX - 1, 16, false, None, &mut out).unwrap());
+		assert!(out.is_empty());
+	}
+}
@@ -0,0 +1,31 @@
+use mlua::{Lua, Error};
+
+use crate::{budget::in_evaluation, state::{ATTACH_KEY, RECORD_KEY, shared_names}};
+
+/// requests are picked up by the session whose evaluation is running, tasks have none
+fn from_session(what: &str) -> Result<(), Error> {
+	if in_evaluation() {
+		Ok(())
+	} else {
+		Err(Error::RuntimeError(format!("{} only works from a session, not from tasks or scripts", what)))
+	}
+}
+
+/// the switch happens once current evaluation completes
+pub fn lua_attach(lua: &Lua, name: String) -> Result<(), Error> {
+	from_session("attach()")?;
+	if name.is_empty() {
+		return Err(Error::RuntimeError("state name can't be empty".into()));
+	}
+	lua.set_named_registry_value(ATTACH_KEY, name)
+}
+
+pub fn lua_states(_: &Lua, _args: ()) -> Result<Vec<String>, Error> {
+	Ok(shared_names())
+}
+
+/// takes effect once current evaluation completes, only if recording is configured
+pub fn lua_record(lua: &Lua, on: bool) -> Result<(), Error> {
+	from_session("record()")?;
+	lua.set_named_registry_value(RECORD_KEY, on)
+}
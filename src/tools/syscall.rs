@@ -3,9 +3,6 @@ use std::{ffi::c_void, num::NonZeroUsize};
 use mlua::{Lua, Error};
 use nix::sys::mman::{mprotect, ProtFlags, mmap, MapFlags, munmap};
 
-use cordy_macro::lua_fn;
-
-#[lua_fn]
 pub fn lua_mprotect(_: &Lua, (addr, size, prot): (usize, usize, i32)) -> Result<(), Error> {
 	match unsafe { mprotect(addr as *mut c_void, size, ProtFlags::from_bits_truncate(prot)) } {
 		Ok(()) => Ok(()),
@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, rc::Rc, task::{Context, Poll}, time::Duration};
+
+use mlua::{Lua, Error, Function, MultiValue, RegistryKey, Table, Variadic, Value};
+use tokio::{sync::{mpsc, oneshot}, time::{Instant, MissedTickBehavior}};
+use tracing::warn;
+
+use crate::{budget::{INTERRUPTED, Interrupt, Limits, sliced}, console::Console};
+
+use super::format::GLOBAL_CONSOLE;
+
+/// background function started with spawn() or every(), to be run by the state
+/// worker so that it outlives the evaluation which started it
+pub struct Job {
+	id: u64,
+	func: RegistryKey,
+	every: Option<Duration>,
+	/// inherited from the evaluation which started it, interrupted on cancel
+	limits: Limits,
+	/// where output and failures go: the session which started this task
+	console: Console,
+	/// resolves when task gets cancelled, or dropped from the registry
+	cancel: oneshot::Receiver<()>,
+}
+
+struct Task {
+	name: String,
+	started: Instant,
+	interrupt: Interrupt,
+	_cancel: oneshot::Sender<()>,
+}
+
+/// running background tasks of a lua state, kept in its app data
+pub struct Tasks {
+	counter: u64,
+	running: BTreeMap<u64, Task>,
+	jobs: mpsc::UnboundedSender<Job>,
+	/// limits of the evaluation in progress, handed down to tasks it starts
+	limits: Limits,
+}
+
+impl Tasks {
+	pub fn new(jobs: mpsc::UnboundedSender<Job>) -> Self {
+		Tasks { counter: 0, running: BTreeMap::new(), jobs, limits: Limits::default() }
+	}
+}
+
+/// tasks started from now on run within {limits}, each time they get to run
+pub fn inherit(lua: &Lua, limits: &Limits) {
+	if let Some(mut tasks) = lua.app_data_mut::<Tasks>() {
+		tasks.limits = Limits { interrupt: Interrupt::default(), ..limits.clone() };
+	}
+}
+
+fn seconds(secs: f64) -> Result<Duration, Error> {
+	Duration::try_from_secs_f64(secs)
+		.map_err(|e| Error::RuntimeError(format!("invalid duration {}: {}", secs, e)))
+}
+
+fn schedule(lua: &Lua, func: Function, every: Option<Duration>, name: String) -> Result<u64, Error> {
+	let key = lua.create_registry_value(func)?;
+	let console : Console = lua.globals().get(GLOBAL_CONSOLE)?;
+	let mut tasks = lua.app_data_mut::<Tasks>()
+		.ok_or_else(|| Error::RuntimeError("background tasks are not available in this state".into()))?;
+	tasks.counter += 1;
+	let id = tasks.counter;
+	let (cancel_tx, cancel_rx) = oneshot::channel();
+	let limits = Limits { interrupt: Interrupt::default(), ..tasks.limits.clone() };
+	let interrupt = limits.interrupt.clone();
+	tasks.jobs.send(Job { id, func: key, every, limits, console, cancel: cancel_rx })
+		.map_err(|_| Error::RuntimeError("state is not accepting tasks".into()))?;
+	tasks.running.insert(id, Task { name, started: Instant::now(), interrupt, _cancel: cancel_tx });
+	Ok(id)
+}
+
+/// polls inner future with its own console installed, putting back the previous one after:
+/// evaluations and other tasks may run in between, each printing to its own session
+struct Consoled<'lua, F> {
+	lua: &'lua Lua,
+	console: Console,
+	inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Consoled<'_, F> {
+	type Output = F::Output;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let globals = self.lua.globals();
+		let prev = globals.get::<_, Console>(GLOBAL_CONSOLE).ok();
+		if let Err(e) = globals.set(GLOBAL_CONSOLE, self.console.clone()) {
+			warn!("could not set task console: {}", e);
+		}
+		let res = self.inner.as_mut().poll(cx);
+		if let Some(prev) = prev {
+			if let Err(e) = globals.set(GLOBAL_CONSOLE, prev) {
+				warn!("could not restore console after task: {}", e);
+			}
+		}
+		res
+	}
+}
+
+/// run a job until it completes, fails or gets cancelled. output and errors go to the
+/// console of the session which started it. lua code hogging the state gets stopped
+pub async fn run(lua: Rc<Lua>, job: Job) {
+	let Job { id, func, every, limits, console, mut cancel } = job;
+	let task = Consoled { lua: &lua, console: console.clone(), inner: Box::pin(work(&lua, &func, every)) };
+	let res = tokio::select! {
+		res = sliced(&limits, task) => res,
+		_ = &mut cancel => Ok(Ok(())),
+	};
+
+	let failure = match res {
+		Ok(Ok(())) | Err(INTERRUPTED) => None,
+		Ok(Err(e)) => Some(e.to_string()),
+		Err(reason) => Some(reason.to_string()),
+	};
+	if let Some(e) = failure {
+		if lua.app_data_ref::<Tasks>().is_some_and(|t| t.running.contains_key(&id)) {
+			let _ = console.send(format!("! task #{} failed: {}\n", id, e));
+		}
+	}
+	if let Some(mut tasks) = lua.app_data_mut::<Tasks>() {
+		tasks.running.remove(&id);
+	}
+	if let Err(e) = lua.remove_registry_value(func) {
+		warn!("could not release task #{} function: {}", id, e);
+	}
+}
+
+async fn work(lua: &Lua, func: &RegistryKey, every: Option<Duration>) -> Result<(), Error> {
+	let func : Function = lua.registry_value(func)?;
+	match every {
+		None => func.call_async::<_, ()>(()).await,
+		Some(period) => {
+			let mut ticker = tokio::time::interval(period);
+			ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+			loop {
+				ticker.tick().await;
+				func.call_async::<_, ()>(()).await?;
+			}
+		},
+	}
+}
+
+pub async fn lua_sleep(_: &Lua, secs: f64) -> Result<(), Error> {
+	tokio::time::sleep(seconds(secs)?).await;
+	Ok(())
+}
+
+pub async fn lua_timeout<'lua>(_: &'lua Lua, (secs, func, args): (f64, Function<'lua>, Variadic<Value<'lua>>)) -> Result<MultiValue<'lua>, Error> {
+	let limit = seconds(secs)?;
+	tokio::time::timeout(limit, func.call_async::<_, MultiValue>(args)).await
+		.map_err(|_| Error::RuntimeError(format!("timed out after {}s", secs)))?
+}
+
+pub fn lua_spawn(lua: &Lua, (func, name): (Function, Option<String>)) -> Result<u64, Error> {
+	schedule(lua, func, None, name.unwrap_or_else(|| "spawn".into()))
+}
+
+pub fn lua_every(lua: &Lua, (secs, func, name): (f64, Function, Option<String>)) -> Result<u64, Error> {
+	let period = seconds(secs)?;
+	if period.is_zero() {
+		return Err(Error::RuntimeError("interval can't be zero".into()));
+	}
+	schedule(lua, func, Some(period), name.unwrap_or_else(|| format!("every {}s", secs)))
+}
+
+pub fn lua_tasks(lua: &Lua, _args: ()) -> Result<Vec<Table<'_>>, Error> {
+	let mut out = vec![];
+	if let Some(tasks) = lua.app_data_ref::<Tasks>() {
+		for (id, task) in tasks.running.iter() {
+			let entry = lua.create_table()?;
+			entry.set("id", *id)?;
+			entry.set("name", task.name.as_str())?;
+			entry.set("uptime", task.started.elapsed().as_secs_f64())?;
+			out.push(entry);
+		}
+	}
+	Ok(out)
+}
+
+/// returns false if no task with given id is running
+pub fn lua_cancel(lua: &Lua, id: u64) -> Result<bool, Error> {
+	match lua.app_data_mut::<Tasks>() {
+		Some(mut tasks) => match tasks.running.remove(&id) {
+			Some(task) => {
+				task.interrupt.trigger(); // in case a task cancels itself, or another one
+				Ok(true)
+			},
+			None => Ok(false),
+		},
+		None => Ok(false),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::sync::mpsc;
+
+	use crate::{budget::Limits, console::{Console, Output}, state::LuaState};
+
+	fn texts(rx: &mut mpsc::UnboundedReceiver<Output>) -> String {
+		let mut out = String::new();
+		while let Ok(msg) = rx.try_recv() {
+			if let Output::Text(txt) = msg {
+				out.push_str(&txt);
+			}
+		}
+		out
+	}
+
+	#[tokio::test]
+	async fn cancel_stops_tasks() {
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let console = Console::from(tx);
+		let state = LuaState::run("cancel-test".into(), Some(console.clone())).unwrap();
+		state.eval("id = every(0.01, function() end) spawn(function() cancel(id) while true do sleep(10) end end)".into(), console.clone(), Limits::default()).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		state.eval("cancel(2)".into(), console.clone(), Limits::default()).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		let res = state.eval("#tasks()".into(), console, Limits::default()).await.unwrap();
+		match res.outcome {
+			crate::state::Outcome::Values(vals) => assert_eq!(vals[0].1, "0"),
+			_ => panic!("unexpected outcome"),
+		}
+		assert!(!texts(&mut rx).contains("failed"));
+	}
+
+	#[tokio::test]
+	async fn tasks_print_to_the_session_which_started_them() {
+		let (a_tx, mut a_rx) = mpsc::unbounded_channel();
+		let (b_tx, mut b_rx) = mpsc::unbounded_channel();
+		let (a, b) = (Console::from(a_tx), Console::from(b_tx));
+		let state = LuaState::run("console-test".into(), Some(a.clone())).unwrap();
+		state.eval("spawn(function() sleep(0.05) log('from task') end)".into(), a.clone(), Limits::default()).await.unwrap();
+		// task wakes up while this evaluation holds the console
+		state.eval("sleep(0.1) log('from b')".into(), b, Limits::default()).await.unwrap();
+		let (a_out, b_out) = (texts(&mut a_rx), texts(&mut b_rx));
+		assert!(a_out.contains("from task"));
+		assert!(!a_out.contains("from b"));
+		assert!(b_out.contains("from b"));
+		assert!(!b_out.contains("from task"));
+	}
+}
@@ -0,0 +1,120 @@
+use std::{fs::{File, OpenOptions}, io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use tracing::warn;
+
+/// audit trail of a session: what was sent, what came back, and when.
+/// many sessions can share the same file, every line carries session id
+pub struct Transcript {
+	file: File,
+	session: String,
+	enabled: bool,
+}
+
+impl Transcript {
+	/// {path} can be a file, appended to, or a directory getting one file per session
+	pub fn open(path: &Path, session: &str, peer: &str) -> Option<Self> {
+		let path = if path.is_dir() {
+			let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+			path.join(format!("cordy-{}-{}-{}.log", std::process::id(), session.trim_start_matches('#'), secs))
+		} else {
+			path.to_path_buf()
+		};
+		match OpenOptions::new().create(true).append(true).mode(0o600).open(&path) {
+			Ok(file) => {
+				let mut transcript = Transcript { file, session: session.to_string(), enabled: true };
+				transcript.write('~', &format!("session started by {}", peer));
+				Some(transcript)
+			},
+			Err(e) => {
+				warn!("could not open transcript {}, not recording: {}", path.display(), e);
+				None
+			},
+		}
+	}
+
+	/// pause or resume recording, noting it in the transcript
+	pub fn set_enabled(&mut self, enabled: bool) {
+		if enabled == self.enabled {
+			return;
+		}
+		self.enabled = true;
+		self.write('~', if enabled { "recording resumed" } else { "recording paused" });
+		self.enabled = enabled;
+	}
+
+	pub fn input(&mut self, txt: &str) {
+		for line in txt.lines() {
+			self.write('<', line);
+		}
+	}
+
+	/// console output, errors are told apart by their `!` prefix
+	pub fn output(&mut self, txt: &str) {
+		for line in txt.lines().filter(|l| !l.trim().is_empty()) {
+			match line.strip_prefix("! ") {
+				Some(err) => self.write('!', err),
+				None => self.write('>', line),
+			}
+		}
+	}
+
+	fn write(&mut self, kind: char, line: &str) {
+		if !self.enabled {
+			return;
+		}
+		// a single write per line, so sessions sharing a file don't mix up
+		let record = format!("{} {} {} {}\n", timestamp(), self.session, kind, line);
+		if let Err(e) = self.file.write_all(record.as_bytes()) {
+			warn!("could not write transcript for session {}: {}", self.session, e);
+		}
+	}
+}
+
+impl Drop for Transcript {
+	fn drop(&mut self) {
+		self.enabled = true;
+		self.write('~', "session closed");
+	}
+}
+
+/// current UTC time as `YYYY-MM-DDTHH:MM:SS.mmmZ`
+fn timestamp() -> String {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	utc(now.as_secs() as i64, now.subsec_millis())
+}
+
+fn utc(secs: i64, millis: u32) -> String {
+	let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+	// days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, millis
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn known_dates() {
+		assert_eq!(utc(0, 0), "1970-01-01T00:00:00.000Z");
+		assert_eq!(utc(-1, 999), "1969-12-31T23:59:59.999Z");
+		assert_eq!(utc(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+		assert_eq!(utc(1_709_251_199, 0), "2024-02-29T23:59:59.000Z");
+		assert_eq!(utc(1_735_689_599, 0), "2024-12-31T23:59:59.000Z");
+		assert_eq!(utc(4_102_444_800, 0), "2100-01-01T00:00:00.000Z");
+		assert_eq!(utc(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+	}
+}